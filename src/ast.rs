@@ -1,84 +1,104 @@
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 use crate::{
-    compiler::{CompileIR, Compiler, IRBuilder, IVals},
-    ir::{Address, Class, Handler as IRHandler, Object as IRObject, Param, Selector, Value, IR},
+    compiler::{drop_locals, CompileError, CompileIR, Compiler, IRBuilder, IVals},
+    grammar::{Inlined, Position, Source},
+    ir::{
+        Address, Class, Handler as IRHandler, InlineCache, Object as IRObject, Param, Selector,
+        Value, IR,
+    },
+    optimizer::inline_handler,
     parser::{Parse, ParseError},
 };
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Binding {
-    Identifier(String),
-    VarIdentifier(String),
-    DoIdentifier(String),
-    Destructure(Vec<(String, Binding)>),
+    Identifier(String, Option<Source>),
+    VarIdentifier(String, Option<Source>),
+    DoIdentifier(String, Option<Source>),
+    Destructure(Vec<(String, Binding)>, Option<Source>),
 }
 impl Binding {
+    fn source(&self) -> Option<Source> {
+        match self {
+            Self::Identifier(_, source)
+            | Self::VarIdentifier(_, source)
+            | Self::DoIdentifier(_, source)
+            | Self::Destructure(_, source) => *source,
+        }
+    }
+    fn invalid(&self, statement: &str) -> CompileIR {
+        Err(CompileError::InvalidBinding(statement.to_string()).with_source(self.source()))
+    }
     fn compile_let(self, compiler: &mut Compiler) -> CompileIR {
         match self {
-            Self::Identifier(name) => {
+            Self::Identifier(name, _) => {
                 compiler.add_let(name);
                 Ok(IRBuilder::new())
             }
-            Self::Destructure(items) => {
+            Self::Destructure(items, _) => {
                 let addr = compiler.add_anon();
                 let mut ir = IRBuilder::new();
                 for (key, binding) in items {
                     ir.push(IR::Local(addr));
-                    ir.push(IR::Send(key, 0));
+                    ir.push(IR::send(&key, 0));
                     ir.append(binding.compile_let(compiler)?);
                 }
                 Ok(ir)
             }
-            _ => todo!(),
+            _ => self.invalid("let"),
         }
     }
     fn compile_export(self, compiler: &mut Compiler) -> CompileIR {
         match self {
-            Self::Identifier(name) => {
-                compiler.add_let_export(name)?;
+            Self::Identifier(name, source) => {
+                compiler
+                    .add_let_export(name)
+                    .map_err(|err| err.with_source(source))?;
                 Ok(IRBuilder::new())
             }
-            Self::Destructure(items) => {
+            Self::Destructure(items, _) => {
                 let addr = compiler.add_anon();
                 let mut ir = IRBuilder::new();
                 for (key, binding) in items {
                     ir.push(IR::Local(addr));
-                    ir.push(IR::Send(key, 0));
+                    ir.push(IR::send(&key, 0));
                     ir.append(binding.compile_export(compiler)?);
                 }
                 Ok(ir)
             }
-            _ => todo!(),
+            _ => self.invalid("export"),
         }
     }
     fn compile_var(self, compiler: &mut Compiler) -> CompileIR {
         match self {
-            Self::Identifier(name) => compiler.add_var(name),
-            _ => todo!(),
+            Self::Identifier(name, _) => compiler.add_var(name),
+            _ => self.invalid("var"),
         }
     }
     fn compile_set(self, compiler: &mut Compiler) -> CompileIR {
         match self {
-            Self::Identifier(name) => compiler.set(name),
-            _ => todo!(),
+            Self::Identifier(name, source) => {
+                compiler.set(name).map_err(|err| err.with_source(source))
+            }
+            _ => self.invalid("set"),
         }
     }
     fn compile_param(self, compiler: &mut Compiler) -> ParamResult {
         match self {
-            Self::Identifier(name) => {
+            Self::Identifier(name, _) => {
                 compiler.add_let(name);
                 ParamResult::Value
             }
-            Self::Destructure(items) => {
+            Self::Destructure(items, _) => {
                 let addr = compiler.add_anon();
                 ParamResult::Destructure(addr, items)
             }
-            Self::VarIdentifier(name) => {
+            Self::VarIdentifier(name, _) => {
                 compiler.add_var_param(name);
                 ParamResult::Var
             }
-            Self::DoIdentifier(name) => {
+            Self::DoIdentifier(name, _) => {
                 compiler.add_do_param(name);
                 ParamResult::Do
             }
@@ -107,7 +127,7 @@ impl ParamResult {
                 let mut ir = IRBuilder::new();
                 for (key, binding) in items {
                     ir.push(IR::Local(addr));
-                    ir.push(IR::Send(key, 0));
+                    ir.push(IR::send(&key, 0));
                     ir.append(binding.compile_let(compiler)?);
                 }
                 Ok(ir)
@@ -125,7 +145,8 @@ pub enum Stmt {
     Let(Binding, Expr, IsExport),
     Var(Binding, Expr),
     Set(Binding, Expr),
-    Import(Binding, String, IsExport),
+    Import(Binding, String, IsExport, Option<Source>),
+    Provide(Vec<(String, Expr)>),
     Return(Expr),
 }
 
@@ -135,7 +156,7 @@ impl Stmt {
             Self::Expr(expr) => expr.compile(compiler),
             Self::Let(binding, expr, is_export) => {
                 if !is_export {
-                    if let (Binding::Identifier(key), Some(value)) =
+                    if let (Binding::Identifier(key, _), Some(value)) =
                         (binding.clone(), expr.get_const(compiler))
                     {
                         compiler.add_const(key, value);
@@ -144,6 +165,13 @@ impl Stmt {
                 }
 
                 let mut ir = expr.compile_with_binding(compiler, &binding)?;
+                // objects without ivals are constants too, so sends to them can be inlined
+                if let (true, Binding::Identifier(key, _), Some(IR::Constant(value))) =
+                    (compiler.allow_inline() && !is_export, &binding, ir.single())
+                {
+                    compiler.add_const(key.to_string(), value.clone());
+                    return Ok(IRBuilder::new());
+                }
                 if is_export {
                     ir.append(binding.compile_export(compiler)?);
                 } else {
@@ -162,8 +190,12 @@ impl Stmt {
                 ir.append(binding.compile_set(compiler)?);
                 Ok(ir)
             }
-            Self::Import(binding, name, is_export) => {
-                let mut ir = IRBuilder::from(vec![IR::Module(name)]);
+            Self::Import(binding, name, is_export, source) => {
+                let mut ir = IRBuilder::new();
+                ir.push_at(
+                    IR::Module(compiler.module_name(name)),
+                    compiler.position(source),
+                );
                 if is_export {
                     ir.append(binding.compile_export(compiler)?);
                 } else {
@@ -171,6 +203,14 @@ impl Stmt {
                 }
                 Ok(ir)
             }
+            Self::Provide(items) => {
+                let mut ir = IRBuilder::new();
+                for (key, expr) in items {
+                    ir.append(expr.compile(compiler)?);
+                    ir.push(IR::Provide(key));
+                }
+                Ok(ir)
+            }
             Self::Return(expr) => {
                 let mut ir = expr.compile(compiler)?;
                 ir.push(IR::Return);
@@ -178,6 +218,12 @@ impl Stmt {
             }
         }
     }
+    fn needs_scope(&self) -> bool {
+        matches!(
+            self,
+            Self::Let(..) | Self::Var(..) | Self::Import(..) | Self::Provide(..)
+        )
+    }
     // remove unused stack values
     pub fn compile_most(self, compiler: &mut Compiler) -> CompileIR {
         match self {
@@ -208,21 +254,23 @@ pub enum Expr {
     SelfRef,
     Bool(bool),
     Integer(i64),
+    Float(f64),
     String(String),
-    Identifier(String),
-    Send(Selector, Box<Expr>, Vec<Expr>),
-    TrySend(Selector, Box<Expr>, Vec<Expr>, Box<Expr>),
+    Identifier(String, Option<Source>),
+    Send(String, Box<Expr>, Vec<Expr>, Option<Source>),
+    TrySend(String, Box<Expr>, Vec<Expr>, Box<Expr>, Option<Source>),
     Object(Object),
-    VarArg(String),
+    VarArg(String, Option<Source>),
     DoArg(Object),
-    Frame(Selector, Vec<(String, Expr)>),
-    If(Box<Expr>, Vec<Stmt>, Vec<Stmt>),
+    Frame(String, Vec<(String, Expr)>),
+    If(Box<Expr>, Vec<Stmt>, Vec<Stmt>, Option<Source>),
     Paren(Vec<Stmt>),
+    Use(String, Option<Source>),
 }
 
 impl Expr {
     fn send(selector: &str, target: Expr, args: Vec<Expr>) -> Self {
-        Self::Send(selector.to_string(), Box::new(target), args)
+        Self::Send(selector.to_string(), Box::new(target), args, None)
     }
     fn compile(self, compiler: &mut Compiler) -> CompileIR {
         self.compile_base(compiler, None)
@@ -230,15 +278,71 @@ impl Expr {
     fn compile_with_binding(self, compiler: &mut Compiler, binding: &Binding) -> CompileIR {
         self.compile_base(compiler, Some(binding))
     }
-    fn get_direct_handler(&self, compiler: &mut Compiler, selector: &str) -> Option<Rc<IRHandler>> {
+    fn get_direct_handler(
+        &self,
+        compiler: &mut Compiler,
+        selector: Selector,
+    ) -> Option<Rc<IRHandler>> {
         let value = self.get_const(compiler)?;
-        value.class().get(selector).ok()
+        value.class().ok()?.get(selector).ok()
+    }
+    fn compile_send(
+        &self,
+        compiler: &mut Compiler,
+        selector: String,
+        arity: usize,
+        source: Option<Source>,
+    ) -> CompileIR {
+        let mut ir = IRBuilder::new();
+        let position = compiler.position(source);
+        let selector = Selector::new(&selector);
+        match self.get_direct_handler(compiler, selector) {
+            Some(handler) => ir.push_at(IR::SendDirect(handler, arity), position),
+            None => ir.push_at(IR::Send(selector, arity, InlineCache::default()), position),
+        }
+        Ok(ir)
     }
-    fn compile_send(&self, compiler: &mut Compiler, selector: String, arity: usize) -> CompileIR {
-        match self.get_direct_handler(compiler, &selector) {
-            Some(handler) => Ok(IRBuilder::from(vec![IR::SendDirect(handler, arity)])),
-            None => Ok(IRBuilder::from(vec![IR::Send(selector, arity)])),
+    fn compile_inline_send(
+        &self,
+        compiler: &mut Compiler,
+        selector: &str,
+        args: &[IRBuilder],
+        source: Option<Source>,
+    ) -> Option<IRBuilder> {
+        let selector = Selector::new(selector);
+        let (target, handler) = match self {
+            Self::SelfRef => (None, compiler.self_handler(selector)?),
+            _ => {
+                let target = self.get_const(compiler)?;
+                let handler = target.class().ok()?.get(selector).ok()?;
+                (Some(target), handler)
+            }
+        };
+        let args = args
+            .iter()
+            .map(|arg| arg.single().cloned())
+            .collect::<Option<Vec<_>>>()?;
+        let body = inline_handler(
+            target.as_ref(),
+            &handler,
+            &args,
+            compiler.stack_height(),
+            compiler.inline_budget(),
+        )?;
+        // instructions from the handler fail at the send, in the handler they came from
+        let position = compiler.position(source);
+        let mut ir = IRBuilder::new();
+        for (index, item) in body.into_iter().enumerate() {
+            let position = position.clone().map(|position| Position {
+                inlined: Some(Rc::new(Inlined {
+                    selector,
+                    position: handler.positions.get(index).cloned(),
+                })),
+                ..position
+            });
+            ir.push_at(item, position);
         }
+        Some(ir)
     }
     fn get_const(&self, compiler: &mut Compiler) -> Option<Value> {
         if !compiler.allow_inline() {
@@ -249,10 +353,11 @@ impl Expr {
             Self::Unit => Some(Value::Unit),
             Self::Bool(value) => Some(Value::Bool(*value)),
             Self::Integer(value) => Some(Value::Integer(*value)),
+            Self::Float(value) => Some(Value::Float(*value)),
             Self::String(str) => Some(Value::String(Rc::new(str.to_string()))),
-            Self::Identifier(key) => compiler.identifier_const(key),
+            Self::Identifier(key, _) => compiler.identifier_const(key),
             Self::Frame(selector, pairs) => {
-                let class = frame_class(selector.to_string(), &pairs);
+                let class = frame_class(selector.to_string(), pairs);
                 let mut out_args = vec![];
                 for (_, expr) in pairs {
                     out_args.push(expr.get_const(compiler)?);
@@ -271,26 +376,49 @@ impl Expr {
             Self::Unit => Ok(IRBuilder::from(vec![IR::unit()])),
             Self::Bool(value) => Ok(IRBuilder::from(vec![IR::bool(value)])),
             Self::Integer(value) => Ok(IRBuilder::from(vec![IR::int(value)])),
+            Self::Float(value) => Ok(IRBuilder::from(vec![IR::float(value)])),
             Self::String(str) => Ok(IRBuilder::from(vec![IR::string(str)])),
             Self::SelfRef => Ok(IRBuilder::from(vec![IR::SelfRef])),
-            Self::Identifier(name) => compiler.identifier(name),
-            Self::Send(selector, target, args) => {
-                let mut ir = IRBuilder::new();
-                let arity = args.len();
+            Self::Identifier(name, source) => compiler
+                .identifier(name)
+                .map_err(|err| err.with_source(source)),
+            // values pushed for a send are temps while the rest of it compiles, so that
+            // inlined handlers know where their locals go
+            Self::Send(selector, target, args, source) => {
+                // do args are checked against the handler's params when they're sent
+                let has_do_args = args.iter().any(|arg| arg.is_do_arg(compiler));
+                let mut compiled_args = vec![];
                 for arg in args {
-                    ir.append(arg.compile_arg(compiler)?);
+                    compiled_args.push(arg.compile_arg(compiler)?);
+                    compiler.add_temps(1);
+                }
+                let arity = compiled_args.len();
+                compiler.drop_temps(arity);
+                if !has_do_args {
+                    if let Some(ir) =
+                        target.compile_inline_send(compiler, &selector, &compiled_args, source)
+                    {
+                        return Ok(ir);
+                    }
                 }
 
-                let send = target.compile_send(compiler, selector, arity)?;
+                let mut ir = IRBuilder::new();
+                for arg in compiled_args {
+                    ir.append(arg);
+                }
+                let send = target.compile_send(compiler, selector, arity, source)?;
+                compiler.add_temps(arity);
                 ir.append(target.compile_target(compiler)?);
+                compiler.drop_temps(arity);
                 ir.append(send);
                 Ok(ir)
             }
-            Self::TrySend(selector, target, args, or_else) => {
+            Self::TrySend(selector, target, args, or_else, source) => {
                 let mut ir = IRBuilder::new();
                 let arity = args.len();
                 for arg in args {
                     ir.append(arg.compile_arg(compiler)?);
+                    compiler.add_temps(1);
                 }
                 ir.append(
                     Self::DoArg({
@@ -300,8 +428,13 @@ impl Expr {
                     })
                     .compile_arg(compiler)?,
                 );
+                compiler.add_temps(1);
                 ir.append(target.compile_target(compiler)?);
-                ir.push(IR::TrySend(selector, arity));
+                compiler.drop_temps(arity + 1);
+                ir.push_at(
+                    IR::TrySend(Selector::new(&selector), arity),
+                    compiler.position(source),
+                );
                 Ok(ir)
             }
             Self::Object(obj) => obj.compile(compiler, binding),
@@ -311,21 +444,24 @@ impl Expr {
                 let mut ir = IRBuilder::new();
                 for (_, expr) in pairs {
                     ir.append(expr.compile_arg(compiler)?);
+                    compiler.add_temps(1);
                 }
+                compiler.drop_temps(arity);
                 ir.push(IR::object(class, arity));
                 Ok(ir)
             }
-            Self::If(cond, if_true, if_false) => Self::send(
-                ":",
-                *cond,
-                vec![Self::DoArg({
-                    let mut obj = Object::new();
-                    obj.add("true", vec![], if_true);
-                    obj.add("false", vec![], if_false);
-                    obj
-                })],
-            )
-            .compile(compiler),
+            Self::If(cond, if_true, if_false, source) => {
+                let else_label = compiler.label();
+                let end_label = compiler.label();
+                let mut ir = cond.compile(compiler)?;
+                ir.push_at(IR::JumpIfFalse(else_label), compiler.position(source));
+                ir.append(Self::compile_branch(compiler, if_true)?);
+                ir.push(IR::Jump(end_label));
+                ir.push(IR::Label(else_label));
+                ir.append(Self::compile_branch(compiler, if_false)?);
+                ir.push(IR::Label(end_label));
+                Ok(ir)
+            }
             Self::Paren(body) => {
                 if body.is_empty() {
                     return Ok(IRBuilder::from(vec![IR::unit()]));
@@ -346,35 +482,78 @@ impl Expr {
                 )
                 .compile(compiler)
             }
-            Self::VarArg(_) => unreachable!(),
+            Self::Use(key, source) => {
+                let mut ir = IRBuilder::new();
+                ir.push_at(IR::Use(key), compiler.position(source));
+                Ok(ir)
+            }
+            Self::VarArg(_, _) => unreachable!(),
             Self::DoArg(_) => unreachable!(),
         }
     }
+    // names bound & values provided in a branch are scoped to it, & its locals are dropped
+    // from under its result at the end
+    fn compile_branch(compiler: &mut Compiler, body: Vec<Stmt>) -> CompileIR {
+        if !body.iter().any(Stmt::needs_scope) {
+            return compiler.body(body);
+        }
+        let provided = body
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Provide(items) => items.len(),
+                _ => 0,
+            })
+            .sum();
+        let base = compiler.stack_height();
+        let outer = compiler.scope();
+        let result = compiler.body(body);
+        let locals = compiler.stack_height() - base;
+        compiler.end_scope(outer);
+        let mut ir = result?;
+        if provided > 0 {
+            ir.push(IR::Unprovide(provided));
+        }
+        ir.append(IRBuilder::from(drop_locals(base, locals)));
+        Ok(ir)
+    }
+    fn is_do_arg(&self, compiler: &mut Compiler) -> bool {
+        match self {
+            Self::DoArg(_) => true,
+            Self::Identifier(name, _) => compiler.is_do(name),
+            _ => false,
+        }
+    }
     fn compile_arg(self, compiler: &mut Compiler) -> CompileIR {
         match self {
-            Self::VarArg(name) => compiler.var_arg(name),
+            Self::VarArg(name, source) => compiler
+                .var_arg(name)
+                .map_err(|err| err.with_source(source)),
             Self::DoArg(obj) => obj.compile_do(compiler),
-            Self::Identifier(name) => compiler.arg_identifier(name),
+            Self::Identifier(name, source) => compiler
+                .arg_identifier(name)
+                .map_err(|err| err.with_source(source)),
             _ => self.compile(compiler),
         }
     }
     fn compile_target(self, compiler: &mut Compiler) -> CompileIR {
         match self {
             Self::DoArg(obj) => obj.compile_do(compiler),
-            Self::Identifier(name) => compiler.target_identifier(name),
+            Self::Identifier(name, source) => compiler
+                .target_identifier(name)
+                .map_err(|err| err.with_source(source)),
             _ => self.compile(compiler),
         }
     }
-    pub fn as_binding(self) -> Parse<Binding> {
+    pub fn into_binding(self) -> Parse<Binding> {
         match self {
-            Self::Identifier(name) => Ok(Binding::Identifier(name)),
+            Self::Identifier(name, source) => Ok(Binding::Identifier(name, source)),
             _ => Err(ParseError::expected("set binding")),
         }
     }
     pub fn set_target(&self) -> Parse<Binding> {
         match self {
-            Self::Identifier(name) => Ok(Binding::Identifier(name.to_string())),
-            Self::Send(_, target, _) => target.set_target(),
+            Self::Identifier(name, source) => Ok(Binding::Identifier(name.to_string(), *source)),
+            Self::Send(_, target, _, _) => target.set_target(),
             _ => Err(ParseError::expected("set target")),
         }
     }
@@ -406,21 +585,21 @@ impl Object {
         params: Vec<Binding>,
         body: Vec<Stmt>,
     ) -> Parse<()> {
-        if self
-            .handlers
-            .insert(selector, Handler { params, body })
-            .is_some()
-        {
-            todo!()
+        if self.handlers.contains_key(&selector) {
+            return Err(ParseError::DuplicateHandler(selector));
         }
+        self.handlers.insert(selector, Handler { params, body });
         Ok(())
     }
     fn compile(self, compiler: &mut Compiler, binding: Option<&Binding>) -> CompileIR {
         let mut class = Class::new();
         let mut ivals = IVals::new();
 
-        for (selector, handler) in self.handlers {
-            compiler.handler(ivals);
+        // handlers are compiled in order, so that sends to self can inline earlier ones
+        let mut handlers = self.handlers.into_iter().collect::<Vec<_>>();
+        handlers.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (selector, handler) in handlers {
+            compiler.handler(ivals, class.clone().rc());
             let mut param_results = vec![];
             for param in handler.params {
                 param_results.push(param.compile_param(compiler));
@@ -440,7 +619,8 @@ impl Object {
 
             ir.append(compiler.body(handler.body)?);
 
-            class.add_handler(selector, params, ir.build());
+            let (body, positions) = compiler.build(ir);
+            class.add_handler_with_positions(Selector::new(&selector), params, body, positions);
             ivals = compiler.end_handler();
         }
         let arity = ivals.count();
@@ -465,7 +645,8 @@ impl Object {
             }
             ir.append(compiler.body(handler.body)?);
 
-            class.add_handler(selector, params, ir.build());
+            let (body, positions) = compiler.build(ir);
+            class.add_handler_with_positions(Selector::new(&selector), params, body, positions);
             ivals = compiler.end_handler();
         }
         let arity = ivals.count();
@@ -488,7 +669,10 @@ pub fn frame_class(selector: String, pairs: &[(String, Expr)]) -> Rc<Class> {
         return class;
     }
 
-    let mut class = Class::new();
+    let mut class = Class::new_frame(
+        Selector::new(&selector),
+        pairs.iter().map(|(key, _)| key.to_string()).collect(),
+    );
     // match
     class.add(":", vec![Param::Do], {
         let mut builder = IRBuilder::new();
@@ -496,7 +680,7 @@ pub fn frame_class(selector: String, pairs: &[(String, Expr)]) -> Rc<Class> {
             builder.push(IR::IVal(i));
         }
         builder.push(IR::Local(0));
-        builder.push(IR::Send(selector.to_string(), pairs.len()));
+        builder.push(IR::send(&selector, pairs.len()));
         builder.build()
     });
 
@@ -514,14 +698,14 @@ pub fn frame_class(selector: String, pairs: &[(String, Expr)]) -> Rc<Class> {
         class.add(
             ":into:",
             vec![Param::Value, Param::Value],
-            vec![IR::Send(format!("{}:", &selector), 1)],
+            vec![IR::send(&format!("{}:", &selector), 1)],
         );
     } else {
         for (i, (key, _)) in pairs.iter().enumerate() {
             // getter
-            class.add_handler(key.to_string(), vec![], vec![IR::IVal(i)]);
+            class.add(key, vec![], vec![IR::IVal(i)]);
             // setter
-            class.add_handler(format!("{}:", &key), vec![Param::Value], {
+            class.add(&format!("{}:", &key), vec![Param::Value], {
                 let mut builder = IRBuilder::new();
                 for j in 0..pairs.len() {
                     if i == j {
@@ -534,15 +718,15 @@ pub fn frame_class(selector: String, pairs: &[(String, Expr)]) -> Rc<Class> {
                 builder.build()
             });
             // update
-            class.add_handler(
-                format!("-> {}:", &key),
+            class.add(
+                &format!("-> {}:", &key),
                 vec![Param::Do],
                 vec![
                     IR::IVal(i),
                     IR::Local(0),
                     IR::send(":", 1),
                     IR::SelfRef,
-                    IR::Send(format!("{}:", &key), 1),
+                    IR::send(&format!("{}:", &key), 1),
                 ],
             );
         }
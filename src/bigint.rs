@@ -0,0 +1,481 @@
+use std::cmp::Ordering;
+
+// signed arbitrary precision integer, as a sign & little endian base 2^32 magnitude.
+// magnitudes have no trailing zero limbs, and zero is never negative
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut magnitude: Vec<u32>) -> Self {
+        while magnitude.last() == Some(&0) {
+            magnitude.pop();
+        }
+        let negative = negative && !magnitude.is_empty();
+        BigInt {
+            negative,
+            magnitude,
+        }
+    }
+    pub fn zero() -> Self {
+        BigInt::new(false, vec![])
+    }
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+        let mut value = 0u64;
+        for (i, limb) in self.magnitude.iter().enumerate() {
+            value |= (*limb as u64) << (32 * i);
+        }
+        if self.negative {
+            0i64.checked_sub_unsigned(value)
+        } else {
+            i64::try_from(value).ok()
+        }
+    }
+    pub fn to_f64(&self) -> f64 {
+        let value = self
+            .magnitude
+            .iter()
+            .rev()
+            .fold(0.0, |sum, limb| sum * 4294967296.0 + *limb as f64);
+        if self.negative {
+            -value
+        } else {
+            value
+        }
+    }
+    pub fn to_usize(&self) -> Option<usize> {
+        self.to_i64().and_then(|value| usize::try_from(value).ok())
+    }
+    pub fn count_ones(&self) -> Option<u64> {
+        if self.negative {
+            return None;
+        }
+        Some(
+            self.magnitude
+                .iter()
+                .map(|limb| limb.count_ones() as u64)
+                .sum(),
+        )
+    }
+
+    pub fn neg(&self) -> Self {
+        BigInt::new(!self.negative, self.magnitude.clone())
+    }
+    pub fn abs(&self) -> Self {
+        BigInt::new(false, self.magnitude.clone())
+    }
+    pub fn add(&self, other: &Self) -> Self {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, mag_add(&self.magnitude, &other.magnitude));
+        }
+        match mag_cmp(&self.magnitude, &other.magnitude) {
+            Ordering::Less => {
+                BigInt::new(other.negative, mag_sub(&other.magnitude, &self.magnitude))
+            }
+            _ => BigInt::new(self.negative, mag_sub(&self.magnitude, &other.magnitude)),
+        }
+    }
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+    pub fn mul(&self, other: &Self) -> Self {
+        BigInt::new(
+            self.negative != other.negative,
+            mag_mul(&self.magnitude, &other.magnitude),
+        )
+    }
+    // quotient rounded toward negative infinity, or None when dividing by zero
+    pub fn div_floor(&self, other: &Self) -> Option<Self> {
+        let (quotient, _) = self.div_mod_floor(other)?;
+        Some(quotient)
+    }
+    // remainder of div_floor, which has the sign of the divisor
+    pub fn mod_floor(&self, other: &Self) -> Option<Self> {
+        let (_, rem) = self.div_mod_floor(other)?;
+        Some(rem)
+    }
+    fn div_mod_floor(&self, other: &Self) -> Option<(Self, Self)> {
+        let (quotient, rem) = self.div_rem(other)?;
+        if !rem.is_zero() && rem.negative != other.negative {
+            Some((quotient.sub(&BigInt::from(1)), rem.add(other)))
+        } else {
+            Some((quotient, rem))
+        }
+    }
+    fn div_rem(&self, other: &Self) -> Option<(Self, Self)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, rem) = mag_div_rem(&self.magnitude, &other.magnitude);
+        Some((
+            BigInt::new(self.negative != other.negative, quotient),
+            BigInt::new(self.negative, rem),
+        ))
+    }
+
+    pub fn shl(&self, bits: usize) -> Self {
+        BigInt::new(self.negative, mag_shl(&self.magnitude, bits))
+    }
+    // rounds toward negative infinity, like i64 >>
+    pub fn shr(&self, bits: usize) -> Self {
+        if self.negative {
+            let one = BigInt::from(1);
+            let shifted = BigInt::new(false, mag_shr(&self.abs().sub(&one).magnitude, bits));
+            shifted.add(&one).neg()
+        } else {
+            BigInt::new(false, mag_shr(&self.magnitude, bits))
+        }
+    }
+    pub fn not(&self) -> Self {
+        self.add(&BigInt::from(1)).neg()
+    }
+    pub fn and(&self, other: &Self) -> Self {
+        self.bitwise(other, |a, b| a & b)
+    }
+    pub fn or(&self, other: &Self) -> Self {
+        self.bitwise(other, |a, b| a | b)
+    }
+    pub fn xor(&self, other: &Self) -> Self {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+    // bitwise ops act on the infinite two's complement representation
+    fn bitwise(&self, other: &Self, op: fn(u32, u32) -> u32) -> Self {
+        let len = self.magnitude.len().max(other.magnitude.len()) + 1;
+        let left = self.twos_complement(len);
+        let right = other.twos_complement(len);
+        let limbs = left
+            .iter()
+            .zip(right.iter())
+            .map(|(a, b)| op(*a, *b))
+            .collect::<Vec<_>>();
+        let negative = op(self.negative as u32, other.negative as u32) & 1 == 1;
+        BigInt::from_twos_complement(limbs, negative)
+    }
+    fn twos_complement(&self, len: usize) -> Vec<u32> {
+        let mut limbs = self.magnitude.clone();
+        limbs.resize(len, 0);
+        if self.negative {
+            // -x = !(x - 1)
+            let mut limbs = mag_sub(&limbs, &[1]);
+            limbs.resize(len, 0);
+            limbs.iter_mut().for_each(|limb| *limb = !*limb);
+            return limbs;
+        }
+        limbs
+    }
+    fn from_twos_complement(mut limbs: Vec<u32>, negative: bool) -> Self {
+        if negative {
+            limbs.iter_mut().for_each(|limb| *limb = !*limb);
+            BigInt::new(true, mag_add(&limbs, &[1]))
+        } else {
+            BigInt::new(false, limbs)
+        }
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let abs = value.unsigned_abs();
+        BigInt::new(value < 0, vec![abs as u32, (abs >> 32) as u32])
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => mag_cmp(&self.magnitude, &other.magnitude),
+            (true, true) => mag_cmp(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // split into base 10^9 chunks, least significant first
+        let mut chunks = vec![];
+        let mut magnitude = self.magnitude.clone();
+        while !magnitude.is_empty() {
+            let (quotient, rem) = mag_div_rem(&magnitude, &[1_000_000_000]);
+            chunks.push(rem.first().copied().unwrap_or(0));
+            magnitude = quotient;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn trim(mut limbs: Vec<u32>) -> Vec<u32> {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+    limbs
+}
+
+// the limbs of a without leading zeros
+fn significant(a: &[u32]) -> &[u32] {
+    let len = a.iter().rposition(|limb| *limb != 0).map_or(0, |i| i + 1);
+    &a[..len]
+}
+
+fn mag_cmp(a: &[u32], b: &[u32]) -> Ordering {
+    let (a, b) = (significant(a), significant(b));
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn mag_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    out.push(carry as u32);
+    trim(out)
+}
+
+// a - b, where a >= b
+fn mag_sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, limb) in a.iter().enumerate() {
+        let mut diff = *limb as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        out.push(diff as u32);
+    }
+    trim(out)
+}
+
+fn mag_mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let product = *x as u64 * *y as u64 + out[i + j] as u64 + carry;
+            out[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    trim(out)
+}
+
+fn mag_shl(a: &[u32], bits: usize) -> Vec<u32> {
+    let (limbs, bits) = (bits / 32, bits % 32);
+    let mut out = vec![0u32; limbs];
+    let mut carry = 0u32;
+    for limb in a {
+        out.push((limb << bits) | carry);
+        carry = if bits == 0 { 0 } else { limb >> (32 - bits) };
+    }
+    out.push(carry);
+    trim(out)
+}
+
+fn mag_shr(a: &[u32], bits: usize) -> Vec<u32> {
+    let (limbs, bits) = (bits / 32, bits % 32);
+    if limbs >= a.len() {
+        return vec![];
+    }
+    let a = &a[limbs..];
+    let mut out = Vec::with_capacity(a.len());
+    for i in 0..a.len() {
+        let high = if bits == 0 {
+            0
+        } else {
+            a.get(i + 1).map(|limb| limb << (32 - bits)).unwrap_or(0)
+        };
+        out.push((a[i] >> bits) | high);
+    }
+    trim(out)
+}
+
+// long division a limb at a time (Knuth's algorithm D), returns (quotient, remainder)
+fn mag_div_rem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let (a, b) = (significant(a), significant(b));
+    if mag_cmp(a, b) == Ordering::Less {
+        return (vec![], a.to_vec());
+    }
+    if let [divisor] = b {
+        let divisor = *divisor as u64;
+        let mut quotient = vec![0u32; a.len()];
+        let mut rem = 0u64;
+        for i in (0..a.len()).rev() {
+            let value = (rem << 32) | a[i] as u64;
+            quotient[i] = (value / divisor) as u32;
+            rem = value % divisor;
+        }
+        return (trim(quotient), trim(vec![rem as u32]));
+    }
+    // shift both so the divisor's top bit is set, which makes an estimate of each quotient
+    // limb from the top limbs at most 2 too large
+    let shift = b[b.len() - 1].leading_zeros() as usize;
+    let b = mag_shl(b, shift);
+    let mut rem = mag_shl(a, shift);
+    rem.resize(a.len() + 1, 0);
+    let n = b.len();
+    let (top, next) = (b[n - 1] as u64, b[n - 2] as u64);
+    let mut quotient = vec![0u32; a.len() - n + 1];
+    for j in (0..quotient.len()).rev() {
+        let numerator = ((rem[j + n] as u64) << 32) | rem[j + n - 1] as u64;
+        let (mut q, mut r) = (numerator / top, numerator % top);
+        while q > u32::MAX as u64 || q * next > ((r << 32) | rem[j + n - 2] as u64) {
+            q -= 1;
+            r += top;
+            if r > u32::MAX as u64 {
+                break;
+            }
+        }
+        // rem -= q * b, shifted by j limbs
+        let mut carry = 0u64;
+        let mut borrow = 0i64;
+        for i in 0..n {
+            let product = q * b[i] as u64 + carry;
+            carry = product >> 32;
+            let diff = rem[i + j] as i64 - (product & 0xffff_ffff) as i64 - borrow;
+            rem[i + j] = diff as u32;
+            borrow = i64::from(diff < 0);
+        }
+        let diff = rem[j + n] as i64 - carry as i64 - borrow;
+        rem[j + n] = diff as u32;
+        if diff < 0 {
+            // q was still one too large, so add b back
+            q -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let sum = rem[i + j] as u64 + b[i] as u64 + carry;
+                rem[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            rem[j + n] = rem[j + n].wrapping_add(carry as u32);
+        }
+        quotient[j] = q as u32;
+    }
+    rem.truncate(n);
+    (trim(quotient), mag_shr(&rem, shift))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn big(value: i64) -> BigInt {
+        BigInt::from(value)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let max = big(i64::MAX);
+        let sum = max.add(&big(1));
+        assert_eq!(sum.to_string(), "9223372036854775808");
+        assert_eq!(sum.to_i64(), None);
+        assert_eq!(sum.sub(&big(1)), max);
+        assert_eq!(big(i64::MIN).to_i64(), Some(i64::MIN));
+        assert_eq!(big(3).sub(&big(5)), big(-2));
+        assert_eq!(big(-3).mul(&big(5)), big(-15));
+
+        let square = max.mul(&max);
+        assert_eq!(square.to_string(), "85070591730234615847396907784232501249");
+        assert_eq!(square.div_floor(&max), Some(max.clone()));
+        assert_eq!(square.add(&big(5)).mod_floor(&max), Some(big(5)));
+        assert_eq!(big(-7).div_floor(&big(2)), Some(big(-4)));
+        assert_eq!(big(-7).mod_floor(&big(2)), Some(big(1)));
+        assert_eq!(big(7).div_floor(&big(-2)), Some(big(-4)));
+        assert_eq!(big(7).mod_floor(&big(-2)), Some(big(-1)));
+        assert_eq!(big(-8).div_floor(&big(2)), Some(big(-4)));
+        assert_eq!(big(1).div_floor(&BigInt::zero()), None);
+    }
+
+    #[test]
+    fn long_division() {
+        let mut seed = 1u64;
+        let mut limbs = |len: usize| -> Vec<u32> {
+            (0..len)
+                .map(|_| {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    (seed >> 32) as u32
+                })
+                .collect()
+        };
+        let mut cases = vec![
+            // the first estimate of the quotient limb is too large even after correcting it
+            // with the divisor's second limb
+            (
+                vec![0, 0, 0x8000_0000, 0x7fff_ffff],
+                vec![1, 0, 0x8000_0000],
+            ),
+            (
+                vec![0, 0xffff_fffe, 0x8000_0000],
+                vec![0xffff_ffff, 0x8000_0000],
+            ),
+            (vec![3, 0, 0x8000_0000], vec![1, 0, 0x2000_0000]),
+            (vec![5, 7], vec![1, 9]),
+        ];
+        for (a_len, b_len) in [(2, 2), (3, 2), (5, 3), (8, 4), (8, 7), (12, 5)] {
+            for _ in 0..20 {
+                cases.push((limbs(a_len), limbs(b_len)));
+            }
+        }
+        for (a, b) in cases {
+            let (q, r) = mag_div_rem(&a, &b);
+            assert_eq!(mag_cmp(&r, &b), Ordering::Less);
+            assert_eq!(mag_cmp(&mag_add(&mag_mul(&q, &b), &r), &a), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn comparison() {
+        assert!(big(-5) < big(3));
+        assert!(big(-5) < big(-3));
+        assert!(big(i64::MAX).add(&big(1)) > big(i64::MAX));
+        assert_eq!(big(0).neg(), BigInt::zero());
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(big(1).shl(100).shr(99), big(2));
+        assert_eq!(big(-5).shr(1), big(-3));
+        assert_eq!(big(12).and(&big(10)), big(8));
+        assert_eq!(big(12).or(&big(10)), big(14));
+        assert_eq!(big(12).xor(&big(10)), big(6));
+        assert_eq!(big(12).and(&big(4).not()), big(8));
+        assert_eq!(big(-12).and(&big(-10)), big(-12 & -10));
+        assert_eq!(big(-12).or(&big(10)), big(-12 | 10));
+        assert_eq!(big(5).not(), big(-6));
+        assert_eq!(big(1).shl(100).sub(&big(1)).count_ones(), Some(100));
+    }
+}
@@ -1,8 +1,14 @@
 use crate::{
     ast::Stmt,
-    ir::{Address, Class, Index, Value, IR},
+    grammar::{Position, Source, SourceContext, SourceFile},
+    ir::{Address, Class, Handler, Index, Positions, Program, Selector, Value, IR},
+    optimizer::{optimize, Pass},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
 };
-use std::collections::HashMap;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum CompileError {
@@ -13,11 +19,60 @@ pub enum CompileError {
     InvalidDoReference(String),
     DuplicateExport(String),
     InvalidExport(String),
+    InvalidBinding(String),
+    WithSource(Box<CompileError>, Source),
+    InContext(Box<CompileError>, SourceContext),
+}
+
+impl CompileError {
+    // errors keep the innermost source
+    pub fn with_source(self, source: Option<Source>) -> Self {
+        match (self, source) {
+            (err @ Self::WithSource(_, _), _) => err,
+            (err, Some(source)) => Self::WithSource(Box::new(err), source),
+            (err, None) => err,
+        }
+    }
+    pub fn in_context(self, context: &str) -> Self {
+        match self {
+            Self::WithSource(err, source) => Self::InContext(err, source.in_context(context)),
+            _ => self,
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownIdentifier(key) => write!(f, "unknown identifier `{}`", key),
+            Self::InvalidSet(key) => write!(f, "cannot set `{}`, which is not a var", key),
+            Self::InvalidVarReference(key) => {
+                write!(f, "cannot reference var `{}` from inside an object", key)
+            }
+            Self::InvalidVarArg(key) => write!(f, "`{}` is not a var", key),
+            Self::InvalidDoReference(key) => {
+                write!(
+                    f,
+                    "do block `{}` can only be called or passed as a do arg",
+                    key
+                )
+            }
+            Self::DuplicateExport(key) => write!(f, "duplicate export `{}`", key),
+            Self::InvalidExport(key) => write!(f, "cannot export `{}` outside of module root", key),
+            Self::InvalidBinding(statement) => write!(f, "invalid binding for `{}`", statement),
+            Self::WithSource(err, _) => write!(f, "{}", err),
+            Self::InContext(err, context) => write!(f, "{}\n{}", err, context),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct CompilerFlags {
     pub allow_inline: bool,
+    // the most instructions in a handler that's inlined into a send to a constant
+    pub inline_budget: usize,
+    // optimizer passes run on each compiled body, in order
+    pub passes: &'static [Pass],
 }
 
 pub type Compile<T> = Result<T, CompileError>;
@@ -25,24 +80,84 @@ pub type CompileIR = Compile<IRBuilder>;
 
 pub struct IRBuilder {
     ir: Vec<IR>,
+    positions: Positions,
 }
 impl IRBuilder {
     pub fn new() -> Self {
-        IRBuilder { ir: Vec::new() }
+        IRBuilder {
+            ir: Vec::new(),
+            positions: Positions::new(),
+        }
     }
     pub fn from(ir: Vec<IR>) -> Self {
-        IRBuilder { ir }
+        IRBuilder {
+            ir,
+            positions: Positions::new(),
+        }
     }
     pub fn push(&mut self, item: IR) {
         self.ir.push(item);
     }
+    // push an instruction that can fail at the given position
+    pub fn push_at(&mut self, item: IR, position: Option<Position>) {
+        if let Some(position) = position {
+            self.positions.push(self.ir.len(), position);
+        }
+        self.ir.push(item);
+    }
     pub fn append(&mut self, other: IRBuilder) {
-        let mut other_ir = other.build();
+        let (mut other_ir, other_positions) = other.build_with_positions();
+        self.positions.append(other_positions, self.ir.len());
         self.ir.append(&mut other_ir);
     }
     pub fn build(self) -> Vec<IR> {
         self.ir
     }
+    pub fn build_with_positions(self) -> (Vec<IR>, Positions) {
+        (self.ir, self.positions)
+    }
+    // the instruction, if there's only one
+    pub fn single(&self) -> Option<&IR> {
+        match &self.ir[..] {
+            [ir] => Some(ir),
+            _ => None,
+        }
+    }
+    // replaces labels in jumps with the index of the instruction after the label
+    pub fn link(self) -> (Vec<IR>, Positions) {
+        let mut targets = HashMap::new();
+        let mut next_index = 0;
+        for ir in &self.ir {
+            match ir {
+                IR::Label(label) => {
+                    targets.insert(*label, next_index);
+                }
+                _ => next_index += 1,
+            }
+        }
+        let mut out = IRBuilder::new();
+        for (index, ir) in self.ir.into_iter().enumerate() {
+            let position = self.positions.get(index).cloned();
+            let ir = match ir {
+                IR::Label(_) => continue,
+                IR::Jump(label) => IR::Jump(targets[&label]),
+                IR::JumpIfFalse(label) => IR::JumpIfFalse(targets[&label]),
+                ir => ir,
+            };
+            out.push_at(ir, position);
+        }
+        out.build_with_positions()
+    }
+}
+
+// moves the value on top of the stack down over the count locals at base, dropping them
+pub fn drop_locals(base: Address, count: usize) -> Vec<IR> {
+    if count == 0 {
+        return vec![];
+    }
+    let mut ir = vec![IR::Var(base), IR::SetVar];
+    ir.extend((1..count).map(|_| IR::Drop));
+    ir
 }
 
 struct Exports {
@@ -68,7 +183,7 @@ impl Exports {
         let arity = self.exports.len();
         for (i, (key, addr)) in self.exports.into_iter().enumerate() {
             ir.push(IR::Local(addr));
-            class.add_handler(key, vec![], vec![IR::IVal(i)]);
+            class.add(&key, vec![], vec![IR::IVal(i)]);
         }
         ir.push(IR::object(class.rc(), arity));
         Ok(ir)
@@ -142,7 +257,7 @@ impl BindingRecord {
         };
         Ok(IRBuilder::from(ir))
     }
-    fn as_handler_ival(self, next_index: Index, key: &str) -> Compile<Self> {
+    fn into_handler_ival(self, next_index: Index, key: &str) -> Compile<Self> {
         match self {
             Self::Constant(val) => Ok(Self::Constant(val)),
             Self::Local(_) => Ok(Self::IVal(next_index)),
@@ -150,7 +265,7 @@ impl BindingRecord {
             _ => Err(CompileError::InvalidVarReference(key.to_string())),
         }
     }
-    fn as_do_handler_ival(self, next_index: Index) -> Self {
+    fn into_do_handler_ival(self, next_index: Index) -> Self {
         match self {
             Self::Constant(value) => Self::Constant(value),
             Self::Local(_) => Self::IVal(next_index),
@@ -163,9 +278,14 @@ impl BindingRecord {
     }
 }
 
-struct Locals {
+#[derive(Clone)]
+pub struct Locals {
     locals: HashMap<String, BindingRecord>,
     next_index: usize,
+    // values pushed for a send whose args are still being compiled, which sit above the locals
+    temps: usize,
+    // the number of branches being compiled, whose locals are dropped at the end of the branch
+    scopes: usize,
 }
 
 impl Locals {
@@ -173,6 +293,8 @@ impl Locals {
         Locals {
             locals: HashMap::new(),
             next_index: 0,
+            temps: 0,
+            scopes: 0,
         }
     }
     fn get(&self, key: &str) -> Option<BindingRecord> {
@@ -224,7 +346,7 @@ impl IVals {
     fn add(&mut self, key: String, value: BindingRecord) -> Compile<BindingRecord> {
         let next_index = self.ivals.len();
         self.ivals.push(value.clone());
-        let ival = value.as_handler_ival(next_index, &key)?;
+        let ival = value.into_handler_ival(next_index, &key)?;
         if self.map.insert(key, ival.clone()).is_some() {
             panic!("duplicate ival key")
         }
@@ -233,7 +355,7 @@ impl IVals {
     fn add_do(&mut self, key: String, value: BindingRecord) -> Compile<BindingRecord> {
         let next_index = self.ivals.len();
         self.ivals.push(value.clone());
-        let ival = value.as_do_handler_ival(next_index);
+        let ival = value.into_do_handler_ival(next_index);
         if self.map.insert(key, ival.clone()).is_some() {
             panic!("duplicate ival key")
         }
@@ -255,7 +377,8 @@ impl IVals {
 }
 enum CompilerFrame {
     Root(Locals, Exports),
-    Handler(Locals, IVals),
+    // the class being built, with the handlers compiled before this one
+    Handler(Locals, IVals, Rc<Class>),
     Do(Locals, IVals),
 }
 
@@ -263,8 +386,8 @@ impl CompilerFrame {
     fn root() -> Self {
         Self::Root(Locals::new(), Exports::new())
     }
-    fn handler(ivals: IVals) -> Self {
-        Self::Handler(Locals::new(), ivals)
+    fn handler(ivals: IVals, class: Rc<Class>) -> Self {
+        Self::Handler(Locals::new(), ivals, class)
     }
     fn do_handler(ivals: IVals) -> Self {
         Self::Do(Locals::new(), ivals)
@@ -272,35 +395,42 @@ impl CompilerFrame {
     fn get_local(&self, key: &str) -> Option<BindingRecord> {
         match self {
             Self::Root(ls, _) => ls.get(key),
-            Self::Handler(ls, _) => ls.get(key),
+            Self::Handler(ls, _, _) => ls.get(key),
             Self::Do(ls, _) => ls.get(key),
         }
     }
+    fn locals(&self) -> &Locals {
+        match self {
+            Self::Root(ls, _) => ls,
+            Self::Handler(ls, _, _) => ls,
+            Self::Do(ls, _) => ls,
+        }
+    }
     fn locals_mut(&mut self) -> &mut Locals {
         match self {
             Self::Root(ls, _) => ls,
-            Self::Handler(ls, _) => ls,
+            Self::Handler(ls, _, _) => ls,
             Self::Do(ls, _) => ls,
         }
     }
     fn ivals(self) -> IVals {
         match self {
             Self::Root(_, _) => panic!("no ivals at root"),
-            Self::Handler(_, ivals) => ivals,
+            Self::Handler(_, ivals, _) => ivals,
             Self::Do(_, ivals) => ivals,
         }
     }
     fn get_ival(&self, key: &str) -> Option<BindingRecord> {
         match self {
             Self::Root(_, _) => None,
-            Self::Handler(_, ivals) => ivals.get(key),
+            Self::Handler(_, ivals, _) => ivals.get(key),
             Self::Do(_, ivals) => ivals.get(key),
         }
     }
     fn add_ival(&mut self, key: String, value: BindingRecord) -> Compile<BindingRecord> {
         match self {
             Self::Root(_, _) => panic!("no ivals at root"),
-            Self::Handler(_, ivals) => ivals.add(key, value),
+            Self::Handler(_, ivals, _) => ivals.add(key, value),
             Self::Do(_, ivals) => ivals.add_do(key, value),
         }
     }
@@ -321,6 +451,10 @@ impl CompilerFrame {
 pub struct Compiler {
     frames: Vec<CompilerFrame>,
     flags: CompilerFlags,
+    file: Option<Rc<SourceFile>>,
+    // the directory of the module file being compiled, which relative imports are resolved against
+    module_dir: Option<PathBuf>,
+    labels: usize,
 }
 
 impl Compiler {
@@ -328,21 +462,113 @@ impl Compiler {
         Compiler {
             frames: vec![CompilerFrame::root()],
             flags,
+            file: None,
+            module_dir: None,
+            labels: 0,
+        }
+    }
+    // record source positions in compiled code
+    pub fn with_file(mut self, file: SourceFile) -> Self {
+        self.set_file(file);
+        self
+    }
+    pub fn set_file(&mut self, file: SourceFile) {
+        self.file = Some(Rc::new(file));
+    }
+    pub fn with_module_path(mut self, path: &Path) -> Self {
+        self.module_dir = path.parent().map(Path::to_path_buf);
+        self
+    }
+    // "./foo" & "../foo" in a module file are resolved here, as imports in handlers run after
+    // the module has loaded
+    pub fn module_name(&self, name: String) -> String {
+        match &self.module_dir {
+            Some(dir) if name.starts_with("./") || name.starts_with("../") => {
+                dir.join(name).display().to_string()
+            }
+            _ => name,
         }
     }
-    pub fn program(&mut self, program: Vec<Stmt>) -> Compile<Vec<IR>> {
+    pub fn program(&mut self, program: Vec<Stmt>) -> Compile<Program> {
         let out = self.body(program)?;
-        Ok(out.build())
+        Ok(self.build_program(out))
     }
-    pub fn module(&mut self, module: Vec<Stmt>) -> Compile<Vec<IR>> {
+    pub fn module(&mut self, module: Vec<Stmt>) -> Compile<Program> {
         let mut out = self.body(module)?;
         out.append(self.frames.pop().unwrap().compile_exports()?);
-        Ok(out.build())
+        Ok(self.build_program(out))
+    }
+    fn build_program(&self, builder: IRBuilder) -> Program {
+        let (body, positions) = self.build(builder);
+        Program {
+            body: body.into(),
+            positions,
+        }
+    }
+    pub fn position(&self, source: Option<Source>) -> Option<Position> {
+        Some(self.file.as_ref()?.position(source?))
+    }
+    // REPL: root locals persist between lines, and are rolled back when a line fails
+    pub fn root_locals(&self) -> Locals {
+        self.frames[0].locals().clone()
+    }
+    pub fn restore_root_locals(&mut self, locals: Locals) {
+        *self.frames[0].locals_mut() = locals;
+    }
+    pub fn root_stack_size(&self) -> usize {
+        self.frames[0].locals().next_index
+    }
+    // optimizes & links a program or handler body
+    pub fn build(&self, builder: IRBuilder) -> (Vec<IR>, Positions) {
+        optimize(self.flags.passes, builder).link()
+    }
+    // a label for a jump target, unique within the compiler
+    pub fn label(&mut self) -> Index {
+        self.labels += 1;
+        self.labels
     }
     // flags
     pub fn allow_inline(&self) -> bool {
         self.flags.allow_inline
     }
+    pub fn inline_budget(&self) -> usize {
+        self.flags.inline_budget
+    }
+    // a handler of self, when compiling a handler of an object & it's already compiled
+    pub fn self_handler(&self, selector: Selector) -> Option<Rc<Handler>> {
+        if !self.allow_inline() {
+            return None;
+        }
+        match self.frames.last()? {
+            CompilerFrame::Handler(_, _, class) => class.get(selector).ok(),
+            _ => None,
+        }
+    }
+    // the number of values on the stack in the current frame, where an inlined body starts
+    pub fn stack_height(&self) -> Address {
+        let locals = self.frames.last().unwrap().locals();
+        locals.next_index + locals.temps
+    }
+    // names bound until the matching end_scope are only visible in the scope, & its locals are
+    // stacked above the values already on the stack
+    pub fn scope(&mut self) -> Locals {
+        let height = self.stack_height();
+        let locals = self.top_mut().locals_mut();
+        let outer = locals.clone();
+        locals.next_index = height;
+        locals.temps = 0;
+        locals.scopes += 1;
+        outer
+    }
+    pub fn end_scope(&mut self, outer: Locals) {
+        *self.top_mut().locals_mut() = outer;
+    }
+    pub fn add_temps(&mut self, count: usize) {
+        self.top_mut().locals_mut().temps += count;
+    }
+    pub fn drop_temps(&mut self, count: usize) {
+        self.top_mut().locals_mut().temps -= count;
+    }
     // AST methods
     pub fn body(&mut self, mut body: Vec<Stmt>) -> CompileIR {
         let mut builder = IRBuilder::new();
@@ -359,8 +585,8 @@ impl Compiler {
 
         Ok(builder)
     }
-    pub fn handler(&mut self, ivals: IVals) {
-        self.frames.push(CompilerFrame::handler(ivals))
+    pub fn handler(&mut self, ivals: IVals, class: Rc<Class>) {
+        self.frames.push(CompilerFrame::handler(ivals, class))
     }
     pub fn do_handler(&mut self, ivals: IVals) {
         self.frames.push(CompilerFrame::do_handler(ivals))
@@ -372,6 +598,9 @@ impl Compiler {
         self.frames.last_mut().unwrap()
     }
     pub fn add_let_export(&mut self, key: String) -> Compile<()> {
+        if self.top_mut().locals_mut().scopes > 0 {
+            return Err(CompileError::InvalidExport(key));
+        }
         let address = self.top_mut().locals_mut().add_let(key.to_string());
         self.top_mut().add_export(key, address)?;
         Ok(())
@@ -410,6 +639,12 @@ impl Compiler {
     pub fn arg_identifier(&mut self, key: String) -> CompileIR {
         self.get(&key)?.arg_identifier(key)
     }
+    pub fn is_do(&mut self, key: &str) -> bool {
+        matches!(
+            self.get(key),
+            Ok(BindingRecord::Do(_) | BindingRecord::DoIVal(_))
+        )
+    }
     pub fn var_arg(&mut self, key: String) -> CompileIR {
         self.get(&key)?.var_arg(key)
     }
@@ -442,7 +677,7 @@ impl Compiler {
 mod test {
     use crate::{
         ast::{Binding, Expr, Object},
-        ir::{Class, Param},
+        ir::{Class, Param, Selector},
         native::int_class,
     };
 
@@ -451,38 +686,48 @@ mod test {
     fn assert_ok(code: Vec<Stmt>, expected: Vec<IR>) {
         let flags = CompilerFlags {
             allow_inline: false,
+            inline_budget: 0,
+            passes: &[],
         };
-        assert_eq!(Compiler::new(flags).program(code), Ok(expected))
+        assert_eq!(
+            Compiler::new(flags).program(code).map(|p| p.body.to_vec()),
+            Ok(expected)
+        )
     }
 
     fn assert_err(code: Vec<Stmt>, expected: CompileError) {
         let flags = CompilerFlags {
             allow_inline: false,
+            inline_budget: 0,
+            passes: &[],
         };
-        assert_eq!(Compiler::new(flags).program(code), Err(expected))
+        assert_eq!(
+            Compiler::new(flags).program(code).map(|p| p.body.to_vec()),
+            Err(expected)
+        )
     }
 
     fn int(val: i64) -> Expr {
         Expr::Integer(val)
     }
     fn b_ident(name: &str) -> Binding {
-        Binding::Identifier(name.to_string())
+        Binding::Identifier(name.to_string(), None)
     }
     fn b_var(name: &str) -> Binding {
-        Binding::VarIdentifier(name.to_string())
+        Binding::VarIdentifier(name.to_string(), None)
     }
     fn b_do(name: &str) -> Binding {
-        Binding::DoIdentifier(name.to_string())
+        Binding::DoIdentifier(name.to_string(), None)
     }
     fn ident(name: &str) -> Expr {
-        Expr::Identifier(name.to_string())
+        Expr::Identifier(name.to_string(), None)
     }
     fn var_arg(name: &str) -> Expr {
-        Expr::VarArg(name.to_string())
+        Expr::VarArg(name.to_string(), None)
     }
 
     fn send(target: Expr, selector: &str, args: Vec<Expr>) -> Expr {
-        Expr::Send(selector.to_string(), Box::new(target), args)
+        Expr::Send(selector.to_string(), Box::new(target), args, None)
     }
 
     fn let_(binding: Binding, value: Expr) -> Stmt {
@@ -981,9 +1226,13 @@ mod test {
     fn exports() {
         let flags = CompilerFlags {
             allow_inline: false,
+            inline_budget: 0,
+            passes: &[],
         };
         assert_eq!(
-            Compiler::new(flags).module(vec![Stmt::Let(b_ident("foo"), Expr::Integer(123), true),]),
+            Compiler::new(flags)
+                .module(vec![Stmt::Let(b_ident("foo"), Expr::Integer(123), true),])
+                .map(|p| p.body.to_vec()),
             Ok(vec![
                 IR::int(123),
                 IR::unit(),
@@ -1032,7 +1281,7 @@ mod test {
         assert_ok(
             vec![
                 Stmt::Let(
-                    Binding::Destructure(vec![("get x".to_string(), b_ident("x"))]),
+                    Binding::Destructure(vec![("get x".to_string(), b_ident("x"))], None),
                     Expr::Unit,
                     false,
                 ),
@@ -1049,10 +1298,10 @@ mod test {
                 let mut obj = Object::new();
                 obj.add(
                     "foo:",
-                    vec![Binding::Destructure(vec![(
-                        "get x".to_string(),
-                        b_ident("x"),
-                    )])],
+                    vec![Binding::Destructure(
+                        vec![("get x".to_string(), b_ident("x"))],
+                        None,
+                    )],
                     vec![Stmt::Expr(ident("x"))],
                 );
                 obj
@@ -1109,42 +1358,102 @@ mod test {
 
     #[test]
     fn inline_constants() {
-        let flags = CompilerFlags { allow_inline: true };
+        let flags = CompilerFlags {
+            allow_inline: true,
+            inline_budget: 0,
+            passes: &[],
+        };
         assert_eq!(
-            Compiler::new(flags).program(vec![
-                Stmt::Let(b_ident("x"), Expr::Integer(123), false),
-                Stmt::Expr(ident("x")),
-            ]),
+            Compiler::new(flags)
+                .program(vec![
+                    Stmt::Let(b_ident("x"), Expr::Integer(123), false),
+                    Stmt::Expr(ident("x")),
+                ])
+                .map(|p| p.body.to_vec()),
             Ok(vec![IR::int(123)])
         )
     }
 
     #[test]
     fn inline_indirect_constants() {
-        let flags = CompilerFlags { allow_inline: true };
+        let flags = CompilerFlags {
+            allow_inline: true,
+            inline_budget: 0,
+            passes: &[],
+        };
         assert_eq!(
-            Compiler::new(flags).program(vec![
-                Stmt::Let(b_ident("x"), Expr::Integer(123), false),
-                Stmt::Let(b_ident("y"), ident("x"), false),
-                Stmt::Expr(ident("y")),
-            ]),
+            Compiler::new(flags)
+                .program(vec![
+                    Stmt::Let(b_ident("x"), Expr::Integer(123), false),
+                    Stmt::Let(b_ident("y"), ident("x"), false),
+                    Stmt::Expr(ident("y")),
+                ])
+                .map(|p| p.body.to_vec()),
             Ok(vec![IR::int(123)])
         )
     }
 
     #[test]
     fn send_direct() {
-        let flags = CompilerFlags { allow_inline: true };
+        let flags = CompilerFlags {
+            allow_inline: true,
+            inline_budget: 0,
+            passes: &[],
+        };
         assert_eq!(
-            Compiler::new(flags).program(vec![Stmt::Expr(Expr::Send(
-                "-".to_string(),
-                Box::new(Expr::Integer(123)),
-                vec![]
-            )),]),
+            Compiler::new(flags)
+                .program(vec![Stmt::Expr(Expr::Send(
+                    "-".to_string(),
+                    Box::new(Expr::Integer(123)),
+                    vec![],
+                    None
+                )),])
+                .map(|p| p.body.to_vec()),
             Ok(vec![
                 IR::int(123),
-                IR::SendDirect(int_class().get("-").unwrap(), 0)
+                IR::SendDirect(int_class().get(Selector::new("-")).unwrap(), 0)
             ])
         )
     }
+
+    #[test]
+    fn if_branches() {
+        assert_ok(
+            vec![Stmt::Expr(Expr::If(
+                Box::new(Expr::Bool(true)),
+                vec![Stmt::Expr(int(1))],
+                vec![],
+                None,
+            ))],
+            vec![
+                IR::bool(true),
+                IR::JumpIfFalse(4),
+                IR::int(1),
+                IR::Jump(5),
+                IR::unit(),
+            ],
+        );
+        // locals bound in a branch are dropped from under its result
+        assert_ok(
+            vec![Stmt::Expr(Expr::If(
+                Box::new(Expr::Bool(true)),
+                vec![
+                    Stmt::Let(b_ident("x"), int(1), false),
+                    Stmt::Expr(ident("x")),
+                ],
+                vec![],
+                None,
+            ))],
+            vec![
+                IR::bool(true),
+                IR::JumpIfFalse(7),
+                IR::int(1),
+                IR::Local(0),
+                IR::Var(0),
+                IR::SetVar,
+                IR::Jump(8),
+                IR::unit(),
+            ],
+        );
+    }
 }
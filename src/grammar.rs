@@ -3,13 +3,20 @@ use std::{
     rc::Rc,
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+use crate::ir::Selector;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Integer(i64),
+    Float(f64),
     Identifier(String),
     QuotedIdentifier(String),
     Operator(String),
     String(String),
+    // parts of an interpolated string: `"start${`, `}middle${`, `}end"`
+    StringStart(String),
+    StringMiddle(String),
+    StringEnd(String),
     OpenBrace,
     CloseBrace,
     OpenBracket,
@@ -27,6 +34,8 @@ pub enum Token {
     Return,
     Import,
     Export,
+    Provide,
+    Use,
     SelfRef,
     If,
     Then,
@@ -55,8 +64,9 @@ impl Token {
         KEYWORD_TOKENS
             .with(|pair| pair.clone())
             .1
-            .get(self)
-            .map(|s| s.to_string())
+            .iter()
+            .find(|(token, _)| token == self)
+            .map(|(_, s)| s.to_string())
     }
 
     pub fn key_part(self) -> Option<String> {
@@ -75,7 +85,34 @@ impl Token {
         }
     }
 }
-type KeywordTokens = Rc<(HashMap<String, Token>, HashMap<Token, String>)>;
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Integer(value) => write!(f, "{}", value),
+            Token::Float(value) => write!(f, "{:?}", value),
+            Token::Identifier(key) => write!(f, "{}", key),
+            Token::QuotedIdentifier(key) => write!(f, "_{}_", key),
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::String(str) => write!(f, "\"{}\"", str),
+            Token::StringStart(str) => write!(f, "\"{}${{", str),
+            Token::StringMiddle(str) => write!(f, "}}{}${{", str),
+            Token::StringEnd(str) => write!(f, "}}{}\"", str),
+            Token::OpenBrace => write!(f, "{{"),
+            Token::CloseBrace => write!(f, "}}"),
+            Token::OpenBracket => write!(f, "["),
+            Token::CloseBracket => write!(f, "]"),
+            Token::OpenParen => write!(f, "("),
+            Token::CloseParen => write!(f, ")"),
+            Token::Colon => write!(f, ":"),
+            Token::ColonEquals => write!(f, ":="),
+            Token::QuestionMark => write!(f, "?"),
+            Token::EndOfInput => write!(f, "end of input"),
+            tok => write!(f, "{}", tok.to_keyword().unwrap_or_default()),
+        }
+    }
+}
+
+type KeywordTokens = Rc<(HashMap<String, Token>, Vec<(Token, String)>)>;
 
 fn keyword_tokens() -> KeywordTokens {
     let pairs = vec![
@@ -88,6 +125,8 @@ fn keyword_tokens() -> KeywordTokens {
         (Token::SelfRef, "self"),
         (Token::Import, "import"),
         (Token::Export, "export"),
+        (Token::Provide, "provide"),
+        (Token::Use, "use"),
         (Token::If, "if"),
         (Token::Then, "then"),
         (Token::Else, "else"),
@@ -123,31 +162,39 @@ impl Source {
     pub fn new(index: usize, length: usize) -> Self {
         Self { index, length }
     }
+    // the empty source right after this one
+    pub fn end(&self) -> Self {
+        Self::new(self.index + self.length, 0)
+    }
     pub fn in_context(&self, source: &str) -> SourceContext {
+        // source indices count chars, not bytes
+        let chars = source.chars().collect::<Vec<_>>();
+        let index = self.index.min(chars.len());
         let mut line_number = 1;
         let mut line_start = 0;
-        for (i, char) in source.chars().enumerate().take(self.index) {
-            if char == '\n' {
+        for (i, char) in chars.iter().enumerate().take(index) {
+            if *char == '\n' {
                 line_number += 1;
-                line_start = i;
+                line_start = i + 1;
             }
         }
         let max_context_padding = 10;
-        let excerpt_start = line_start.max(self.index - max_context_padding);
-        let mut excerpt_end = self.index + max_context_padding;
-        for (i, char) in source.chars().enumerate().skip(self.index) {
-            if char == '\n' {
+        let excerpt_start = line_start.max(index.saturating_sub(max_context_padding));
+        let mut excerpt_end = (index + max_context_padding).min(chars.len());
+        for (i, char) in chars.iter().enumerate().skip(index) {
+            if *char == '\n' {
                 excerpt_end = i;
                 break;
             }
         }
 
-        let context = source[excerpt_start..excerpt_end].to_string();
+        let context = chars[excerpt_start..excerpt_end].iter().collect();
 
         SourceContext {
             context,
             line: line_number,
-            column: self.index - line_start,
+            column: index - line_start + 1,
+            offset: index - excerpt_start,
         }
     }
 }
@@ -157,9 +204,75 @@ pub struct SourceContext {
     context: String,
     line: usize,
     column: usize,
+    offset: usize,
+}
+
+impl std::fmt::Display for SourceContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "at line {}, column {}:", self.line, self.column)?;
+        writeln!(f, "  {}", self.context)?;
+        write!(f, "  {}^", " ".repeat(self.offset))
+    }
+}
+
+// a line & column in a named source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+    // for an instruction inlined from a handler, the handler it came from
+    pub inlined: Option<Rc<Inlined>>,
 }
 
+// a handler inlined into a send, which stack traces show as if it had been called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inlined {
+    pub selector: Selector,
+    // the position of the instruction in the handler, or None for natives
+    pub position: Option<Position>,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// converts sources in a file into positions
 #[derive(Debug, Clone)]
+pub struct SourceFile {
+    name: Rc<str>,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, code: &str) -> Self {
+        let mut line_starts = vec![0];
+        for (i, char) in code.chars().enumerate() {
+            if char == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        SourceFile {
+            name: name.into(),
+            line_starts,
+        }
+    }
+    pub fn position(&self, source: Source) -> Position {
+        let line = self
+            .line_starts
+            .partition_point(|start| *start <= source.index);
+        Position {
+            file: self.name.clone(),
+            line,
+            column: source.index - self.line_starts[line - 1] + 1,
+            inlined: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TokenWithSource {
     pub token: Token,
     pub source: Source,
@@ -1,15 +1,109 @@
 use std::collections::HashMap;
-use std::{cell::RefCell, rc::Rc};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::{LazyLock, Mutex};
+use std::{
+    cell::{Cell, RefCell},
+    rc::{Rc, Weak},
+};
 
-use crate::native::{array_class, big_int_class, bool_class, int_class, string_class, unit_class};
+use crate::bigint::BigInt;
+use crate::grammar::Position;
+use crate::native::{
+    array_class, big_int_class, bool_class, float_class, int_class, string_class, unit_class,
+};
 use crate::runtime::{Interpreter, Runtime, RuntimeError};
 
 pub type Address = usize;
-pub type Selector = String;
 pub type Index = usize;
 pub type Arity = usize;
 pub type NativeFn = fn(Value, Vec<Value>) -> Runtime<Value>;
 
+// an interned selector, which is cheap to copy, compare & hash
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Selector(u32);
+
+struct Interner {
+    ids: HashMap<&'static str, Selector>,
+    names: Vec<&'static str>,
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> Selector {
+        if let Some(selector) = self.ids.get(name) {
+            return *selector;
+        }
+        // selectors are never freed, and there are only as many as appear in the source
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let selector = Selector(self.names.len() as u32);
+        self.ids.insert(name, selector);
+        self.names.push(name);
+        selector
+    }
+}
+
+// shared between threads, so selectors stay valid in values & programs passed between them
+static INTERNER: LazyLock<Mutex<Interner>> = LazyLock::new(|| {
+    let mut interner = Interner {
+        ids: HashMap::new(),
+        names: vec![],
+    };
+    for name in ["", ":", "true", "false", "+:", "-:", "<:", "=:"] {
+        interner.intern(name);
+    }
+    Mutex::new(interner)
+});
+
+impl Selector {
+    // interned up front, for sends from native code
+    pub const CALL: Selector = Selector(0);
+    pub const COLON: Selector = Selector(1);
+    pub const TRUE: Selector = Selector(2);
+    pub const FALSE: Selector = Selector(3);
+    pub const ADD: Selector = Selector(4);
+    pub const SUB: Selector = Selector(5);
+    pub const LT: Selector = Selector(6);
+    pub const EQ: Selector = Selector(7);
+
+    pub fn new(name: &str) -> Self {
+        INTERNER.lock().unwrap().intern(name)
+    }
+    pub fn as_str(self) -> &'static str {
+        INTERNER.lock().unwrap().names[self.0 as usize]
+    }
+}
+
+impl std::fmt::Debug for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// selectors are already unique small integers, so they hash to themselves
+#[derive(Default)]
+struct SelectorHasher(u64);
+
+impl Hasher for SelectorHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | *byte as u64;
+        }
+    }
+    fn write_u32(&mut self, n: u32) {
+        self.0 = n as u64;
+    }
+}
+
+type SelectorMap<T> = HashMap<Selector, T, BuildHasherDefault<SelectorHasher>>;
+
 type MoreFnInner = fn(&mut Interpreter) -> Runtime<()>;
 
 #[derive(Clone)]
@@ -25,25 +119,135 @@ impl PartialEq for MoreFn {
     }
 }
 
+// inline cache hits & misses on the current thread, across all interpreters
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+thread_local! {
+    static CACHE_STATS: Cell<CacheStats> = const { Cell::new(CacheStats { hits: 0, misses: 0 }) };
+}
+
+pub fn cache_stats() -> CacheStats {
+    CACHE_STATS.get()
+}
+
+pub fn reset_cache_stats() {
+    CACHE_STATS.set(CacheStats::default());
+}
+
+// the number of receiver classes a call site remembers; sends to other classes look up
+// the handler every time
+const INLINE_CACHE_SIZE: usize = 4;
+
+// the handlers a call site has dispatched to, by receiver class. Classes & handlers are held
+// weakly so that caches in a class's own handlers, e.g. of a recursive send, don't keep it
+// alive; a weak pointer also keeps its allocation from being reused by another class while
+// it's in the cache.
+#[derive(Default)]
+pub struct InlineCache(RefCell<Vec<(Weak<Class>, Weak<Handler>)>>);
+
+impl InlineCache {
+    fn lookup(&self, class: Rc<Class>, selector: Selector) -> Runtime<Rc<Handler>> {
+        let mut entries = self.0.borrow_mut();
+        let stats = CACHE_STATS.get();
+        // the class is alive, and so are its handlers
+        let cached = entries
+            .iter()
+            .find(|(cached, _)| std::ptr::eq(cached.as_ptr(), Rc::as_ptr(&class)))
+            .and_then(|(_, handler)| handler.upgrade());
+        if let Some(handler) = cached {
+            CACHE_STATS.set(CacheStats {
+                hits: stats.hits + 1,
+                ..stats
+            });
+            return Ok(handler);
+        }
+        CACHE_STATS.set(CacheStats {
+            misses: stats.misses + 1,
+            ..stats
+        });
+        let handler = class.get(selector)?;
+        if entries.len() < INLINE_CACHE_SIZE {
+            entries.push((Rc::downgrade(&class), Rc::downgrade(&handler)));
+        }
+        Ok(handler)
+    }
+    fn send(
+        &self,
+        ctx: &mut Interpreter,
+        selector: Selector,
+        target: Value,
+        arity: Arity,
+    ) -> Runtime<()> {
+        let handler = self.lookup(target.class()?, selector)?;
+        ctx.send_direct(handler, target, arity)
+    }
+}
+
+// a copied instruction is a new call site
+impl Clone for InlineCache {
+    fn clone(&self) -> Self {
+        InlineCache::default()
+    }
+}
+
+impl std::fmt::Debug for InlineCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<cache>")
+    }
+}
+
+// caches are runtime state, so they don't affect whether instructions are equal
+impl PartialEq for InlineCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+// native fns are compared by address, which is good enough for tests
+#[allow(unpredictable_function_pointer_comparisons)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum IR {
-    Constant(Value),                // ( -- value)
-    Local(Address),                 // ( -- *address)
-    Var(Address),                   // ( -- address)
-    IVal(Index),                    // ( -- instance[index])
-    SelfRef,                        // ( -- self_value)
-    Module(String),                 // ( -- module)
-    Object(Rc<Class>, Arity),       // (...instance -- object)
-    DoObject(Rc<Class>, Arity),     // (...instance -- object)
-    NewSelf(Arity),                 // (...instance -- object)
-    Deref,                          // (address -- *address)
-    SetVar,                         // (value address -- )
-    Send(Selector, Arity),          // (...args target -- result)
-    SendDirect(Rc<Handler>, Arity), // (...args target -- result)
-    TrySend(Selector, Arity),       // (...args target -- result)
-    SendNative(NativeFn, Arity),    // (...args target -- result)
-    Native(MoreFn),                 // (...)
-    Drop,                           // (value --)
+    Constant(Value),                    // ( -- value)
+    Local(Address),                     // ( -- *address)
+    Var(Address),                       // ( -- address)
+    IVal(Index),                        // ( -- instance[index])
+    SelfRef,                            // ( -- self_value)
+    Module(String),                     // ( -- module)
+    Object(Rc<Class>, Arity),           // (...instance -- object)
+    DoObject(Rc<Class>, Arity),         // (...instance -- object)
+    NewSelf(Arity),                     // (...instance -- object)
+    Deref,                              // (address -- *address)
+    SetVar,                             // (value address -- )
+    Send(Selector, Arity, InlineCache), // (...args target -- result)
+    SendDirect(Rc<Handler>, Arity),     // (...args target -- result)
+    TrySend(Selector, Arity),           // (...args target -- result)
+    SendNative(NativeFn, Arity),        // (...args target -- result)
+    Native(MoreFn),                     // (...)
+    Provide(String),                    // (value --)
+    Unprovide(usize),                   // ( -- ) ends the frame's last n provided values
+    Use(String),                        // ( -- provided value)
+    Drop,                               // (value --)
+    Add(InlineCache),                   // (arg target -- result)
+    Sub(InlineCache),                   // (arg target -- result)
+    Lt(InlineCache),                    // (arg target -- result)
+    Eq(InlineCache),                    // (arg target -- result)
+    Jump(Index),                        // ( -- )
+    JumpIfFalse(Index),                 // (condition -- )
+    // a jump target while compiling, which jumps refer to until the body is built
+    Label(Index),
     Return,
     Loop,
 }
@@ -55,6 +259,9 @@ impl IR {
     pub fn int(value: i64) -> Self {
         IR::Constant(Value::Integer(value))
     }
+    pub fn float(value: f64) -> Self {
+        IR::Constant(Value::Float(value))
+    }
     pub fn bool(value: bool) -> Self {
         IR::Constant(Value::Bool(value))
     }
@@ -62,7 +269,7 @@ impl IR {
         IR::Constant(Value::String(Rc::new(value)))
     }
     pub fn send(selector: &str, arity: usize) -> Self {
-        IR::Send(selector.to_string(), arity)
+        IR::Send(Selector::new(selector), arity, InlineCache::default())
     }
     pub fn native(f: MoreFnInner) -> Self {
         IR::Native(MoreFn(f))
@@ -75,21 +282,23 @@ impl IR {
         }
     }
 
-    pub fn eval(self, ctx: &mut Interpreter) -> Runtime<()> {
+    // instructions are borrowed from shared bodies, so only the parts pushed or sent are cloned
+    pub fn eval(&self, ctx: &mut Interpreter) -> Runtime<()> {
         match self {
-            IR::Constant(value) => ctx.push(value),
+            IR::Constant(value) => ctx.push(value.clone()),
             IR::Native(f) => return f.0(ctx),
             IR::SelfRef => {
                 let value = ctx.self_value();
                 ctx.push(value)
             }
             IR::Local(address) => {
+                let address = *address;
                 let local_offset = ctx.local_offset();
                 let value = ctx.get_stack(address + local_offset);
                 ctx.push(value);
             }
             IR::IVal(index) => {
-                let value = ctx.get_ival(index);
+                let value = ctx.get_ival(*index)?;
                 ctx.push(value);
             }
             IR::Var(address) => {
@@ -97,86 +306,196 @@ impl IR {
                 ctx.push(Value::Pointer(absolute_address));
             }
             IR::Object(class, arity) => {
-                let ivals = ctx.take(arity);
-                let value = Value::Object(Object::new(class, ivals).rc());
+                let ivals = ctx.take(*arity);
+                let value = Value::Object(Object::new(class.clone(), ivals).rc());
                 ctx.push(value);
             }
             IR::NewSelf(arity) => {
                 let class = match ctx.self_value() {
                     Value::Object(obj) => obj.class.clone(),
-                    _ => panic!("cannot get class"),
+                    value => return value.type_mismatch("object"),
                 };
-                let ivals = ctx.take(arity);
+                let ivals = ctx.take(*arity);
                 let value = Value::Object(Object::new(class, ivals).rc());
                 ctx.push(value);
             }
             IR::DoObject(class, arity) => {
-                let ivals = ctx.take(arity);
+                let ivals = ctx.take(*arity);
                 let return_from_index = ctx.return_from_index();
                 let self_value = Box::new(ctx.self_value());
                 let value = Value::DoObject(
-                    Object::new(class, ivals).rc(),
+                    Object::new(class.clone(), ivals).rc(),
                     return_from_index,
                     self_value,
                 );
                 ctx.push(value);
             }
             IR::Module(name) => {
-                let value = ctx.load_module(&name)?;
+                let value = ctx.load_module(name)?;
                 ctx.push(value);
             }
             IR::Deref => {
                 let pointer = ctx.pop();
-                let value = ctx.deref_pointer(pointer);
+                let value = ctx.deref_pointer(pointer)?;
                 ctx.push(value);
             }
             IR::SetVar => {
                 let pointer = ctx.pop();
                 let value = ctx.pop();
-                ctx.set_pointer(pointer, value);
+                ctx.set_pointer(pointer, value)?;
             }
-            IR::Send(selector, arity) => {
+            IR::Send(selector, arity, cache) => {
                 let target = ctx.pop();
-                ctx.send(&selector, target, arity)?;
+                cache.send(ctx, *selector, target, *arity)?;
             }
             IR::SendDirect(handler, arity) => {
                 let target = ctx.pop();
-                ctx.send_direct(handler, target, arity)?;
+                ctx.send_direct(handler.clone(), target, *arity)?;
             }
             IR::TrySend(selector, arity) => {
                 let target = ctx.pop();
                 let or_else = ctx.pop();
-                match ctx.send(&selector, target, arity) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        ctx.take(arity);
-                        ctx.send("", or_else, 0)?;
+                // only the target not understanding this selector falls back to or_else
+                match ctx.send(*selector, target, *arity) {
+                    Err(RuntimeError::DoesNotUnderstand(unknown)) if unknown == *selector => {
+                        ctx.take(*arity);
+                        ctx.send(Selector::CALL, or_else, 0)?;
                     }
+                    result => result?,
                 }
             }
             IR::SendNative(f, arity) => {
                 let target = ctx.pop();
-                let args = ctx.take(arity);
+                let args = ctx.take(*arity);
                 let result = f(target, args)?;
                 ctx.push(result);
             }
+            IR::Provide(key) => {
+                let value = ctx.pop();
+                ctx.provide(key.clone(), value);
+            }
+            IR::Unprovide(count) => ctx.unprovide(*count),
+            IR::Use(key) => {
+                let value = ctx.use_provided(key)?;
+                ctx.push(value);
+            }
             IR::Return => ctx.do_return(),
             IR::Loop => ctx.do_loop(),
+            IR::Jump(index) => ctx.jump(*index),
+            IR::JumpIfFalse(index) => {
+                if !ctx.pop().as_bool()? {
+                    ctx.jump(*index);
+                }
+            }
+            IR::Label(_) => unreachable!("labels are removed when a body is built"),
             IR::Drop => {
                 ctx.pop();
             }
+            IR::Add(cache) => int_op(ctx, cache, Selector::ADD, |target, arg| {
+                Some(Value::Integer(target.checked_add(arg)?))
+            })?,
+            IR::Sub(cache) => int_op(ctx, cache, Selector::SUB, |target, arg| {
+                Some(Value::Integer(target.checked_sub(arg)?))
+            })?,
+            IR::Lt(cache) => int_op(ctx, cache, Selector::LT, |target, arg| {
+                Some(Value::Bool(target < arg))
+            })?,
+            IR::Eq(cache) => int_op(ctx, cache, Selector::EQ, |target, arg| {
+                Some(Value::Bool(target == arg))
+            })?,
         }
         Ok(())
     }
 }
 
+// ints are operated on directly; other values, or results that overflow, are sent as usual
+fn int_op(
+    ctx: &mut Interpreter,
+    cache: &InlineCache,
+    selector: Selector,
+    op: fn(i64, i64) -> Option<Value>,
+) -> Runtime<()> {
+    let target = ctx.pop();
+    let arg = ctx.pop();
+    if let (Value::Integer(target), Value::Integer(arg)) = (&target, &arg) {
+        if let Some(result) = op(*target, *arg) {
+            ctx.push(result);
+            return Ok(());
+        }
+    }
+    ctx.push(arg);
+    cache.send(ctx, selector, target, 1)
+}
+
+// source positions of the instructions in a body, sorted by index
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Positions {
+    entries: Vec<(usize, Position)>,
+}
+
+impl Positions {
+    pub fn new() -> Self {
+        Positions { entries: vec![] }
+    }
+    pub fn push(&mut self, index: usize, position: Position) {
+        self.entries.push((index, position));
+    }
+    pub fn append(&mut self, other: Positions, offset: usize) {
+        for (index, position) in other.entries {
+            self.entries.push((index + offset, position));
+        }
+    }
+    pub fn get(&self, index: usize) -> Option<&Position> {
+        self.entries
+            .binary_search_by_key(&index, |(i, _)| *i)
+            .ok()
+            .map(|i| &self.entries[i].1)
+    }
+}
+
+// the top-level code of a program or module
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub body: Rc<[IR]>,
+    pub positions: Positions,
+}
+
+impl From<Vec<IR>> for Program {
+    fn from(body: Vec<IR>) -> Self {
+        Program {
+            body: body.into(),
+            positions: Positions::new(),
+        }
+    }
+}
+
+// a string literal that lexes back to the same string
+fn escape_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    let mut chars = value.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            // `${` would start an interpolation
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            ch if ch.is_control() => out.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Unit,
     Bool(bool),
     Integer(i64),
-    // TODO
-    Bigint(u128),
+    Float(f64),
+    Bigint(Rc<BigInt>),
     String(Rc<String>),
     Object(Rc<Object>),
     DoObject(Rc<Object>, ParentFrameIndex, Box<Value>),
@@ -230,122 +549,203 @@ impl Value {
         })
     }
 
-    pub fn as_bool(&self) -> bool {
+    pub fn as_bool(&self) -> Runtime<bool> {
         match self {
-            Value::Bool(val) => *val,
-            _ => panic!("cannot cast to bool"),
+            Value::Bool(val) => Ok(*val),
+            _ => self.type_mismatch("Bool"),
         }
     }
-    pub fn as_int(&self) -> i64 {
+    pub fn as_int(&self) -> Runtime<i64> {
         match self {
-            Value::Integer(val) => *val,
-            _ => panic!("cannot cast to int"),
+            Value::Integer(val) => Ok(*val),
+            _ => self.type_mismatch("Int"),
         }
     }
-    pub fn as_string(&self) -> Rc<String> {
+    pub fn as_float(&self) -> Runtime<f64> {
         match self {
-            Value::String(str) => str.clone(),
-            _ => panic!("cannot cast to string"),
+            Value::Float(val) => Ok(*val),
+            _ => self.type_mismatch("Float"),
         }
     }
-    pub fn as_array(&self) -> Rc<RefCell<Vec<Value>>> {
+    pub fn as_string(&self) -> Runtime<Rc<String>> {
         match self {
-            Value::MutArray(arr) => arr.value.clone(),
-            _ => panic!("cannot cast to array"),
+            Value::String(str) => Ok(str.clone()),
+            _ => self.type_mismatch("String"),
         }
     }
-    pub fn as_bigint(&self) -> u128 {
+    pub fn as_array(&self) -> Runtime<Rc<RefCell<Vec<Value>>>> {
         match self {
-            Value::Bigint(val) => *val,
-            _ => panic!("cannot cast to bigint"),
+            Value::MutArray(arr) => Ok(arr.value.clone()),
+            _ => self.type_mismatch("Array"),
         }
     }
-    pub fn as_pointer(&self) -> usize {
+    pub fn as_bigint(&self) -> Runtime<Rc<BigInt>> {
         match self {
-            Value::Pointer(address) => *address,
-            _ => panic!("deref a non-pointer"),
+            Value::Bigint(val) => Ok(val.clone()),
+            _ => self.type_mismatch("BigInt"),
+        }
+    }
+    pub fn as_pointer(&self) -> Runtime<usize> {
+        match self {
+            Value::Pointer(address) => Ok(*address),
+            _ => self.type_mismatch("var"),
+        }
+    }
+    fn type_mismatch<T>(&self, expected: &str) -> Runtime<T> {
+        Err(RuntimeError::TypeMismatch(
+            expected.to_string(),
+            self.type_name().to_string(),
+        ))
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Unit => "Unit",
+            Value::Bool(_) => "Bool",
+            Value::Integer(_) => "Int",
+            Value::Float(_) => "Float",
+            Value::Bigint(_) => "BigInt",
+            Value::String(_) => "String",
+            Value::Object(_) => "object",
+            Value::DoObject(_, _, _) => "do object",
+            Value::Pointer(_) => "var",
+            Value::MutArray(_) => "Array",
         }
     }
 
+    // formats the value in goblin syntax, where possible
     pub fn debug(&self) -> String {
         match self {
-            Value::Pointer(_) => panic!("must deref pointer before sending message"),
+            Value::Pointer(_) => "<var>".to_string(),
             Value::Unit => "()".to_string(),
             Value::Integer(value) => value.to_string(),
+            Value::Float(value) => format!("{:?}", value),
             Value::Bigint(value) => value.to_string(),
-            Value::String(value) => format!("\"{}\"", value),
+            Value::String(value) => escape_string(value),
             Value::Bool(value) => value.to_string(),
-            Value::MutArray(items) => items.debug(),
-            Value::Object(obj) => format!(
-                "{{{}}}",
-                obj.ivals
-                    .iter()
-                    .map(|val| val.debug())
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ),
+            Value::MutArray(items) => format!("<array {}>", items.debug()),
+            Value::Object(obj) => match obj.class.frame() {
+                Some((selector, keys)) if keys.is_empty() => format!("[{}]", selector),
+                Some((_, keys)) => format!(
+                    "[{}]",
+                    keys.iter()
+                        .zip(obj.ivals.iter())
+                        .map(|(key, val)| format!("{}: {}", key, val.debug()))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+                None => "<object>".to_string(),
+            },
             Value::DoObject(_, _, _) => "<do object>".to_string(),
         }
     }
+    // the fields that differ between two frames of the same class
+    pub fn frame_diff(&self, other: &Value) -> Option<Vec<(String, Value, Value)>> {
+        match (self, other) {
+            (Value::Object(left), Value::Object(right)) if left.class == right.class => {
+                let (_, keys) = left.class.frame()?;
+                Some(
+                    keys.iter()
+                        .zip(left.ivals.iter().zip(right.ivals.iter()))
+                        .filter(|(_, (l, r))| l != r)
+                        .map(|(key, (l, r))| (key.to_string(), l.clone(), r.clone()))
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
 
-    pub fn class(&self) -> Rc<Class> {
-        match self {
-            Value::Pointer(_) => panic!("must deref pointer before sending message"),
+    pub fn class(&self) -> Runtime<Rc<Class>> {
+        let class = match self {
+            Value::Pointer(_) => return self.type_mismatch("value"),
             Value::Unit => unit_class(),
             Value::Integer(_) => int_class(),
+            Value::Float(_) => float_class(),
             Value::Bigint(_) => big_int_class(),
             Value::String(_) => string_class(),
             Value::Bool(_) => bool_class(),
             Value::MutArray(_) => array_class(),
             Value::Object(obj) => obj.class.clone(),
             Value::DoObject(obj, _, _) => obj.class.clone(),
-        }
+        };
+        Ok(class)
     }
-    pub fn ival(&self, index: Index) -> Value {
-        match self {
-            Value::Object(obj) => obj.ivals[index].clone(),
-            Value::DoObject(obj, _, _) => obj.ivals[index].clone(),
-            _ => todo!(),
+    pub fn ival(&self, index: Index) -> Runtime<Value> {
+        let ivals = match self {
+            Value::Object(obj) => &obj.ivals,
+            Value::DoObject(obj, _, _) => &obj.ivals,
+            _ => return self.type_mismatch("object"),
+        };
+        match ivals.get(index) {
+            Some(value) => Ok(value.clone()),
+            None => Err(RuntimeError::IndexOutOfRange(index as i64, ivals.len())),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Class {
-    handlers: HashMap<Selector, Rc<Handler>>,
+    handlers: SelectorMap<Rc<Handler>>,
+    // the selector & keys of frame classes, for printing
+    frame: Option<(Selector, Vec<String>)>,
 }
 
 impl Class {
     pub fn new() -> Self {
         Class {
-            handlers: HashMap::new(),
+            handlers: SelectorMap::default(),
+            frame: None,
+        }
+    }
+    pub fn new_frame(selector: Selector, keys: Vec<String>) -> Self {
+        Class {
+            handlers: SelectorMap::default(),
+            frame: Some((selector, keys)),
         }
     }
+    pub fn frame(&self) -> Option<&(Selector, Vec<String>)> {
+        self.frame.as_ref()
+    }
     pub fn add(&mut self, selector: &str, params: Vec<Param>, body: Vec<IR>) {
-        self.add_handler(selector.to_string(), params, body)
+        self.add_handler(Selector::new(selector), params, body)
+    }
+    pub fn add_handler(&mut self, selector: Selector, params: Vec<Param>, body: Vec<IR>) {
+        self.add_handler_with_positions(selector, params, body, Positions::new())
     }
-    pub fn add_handler(&mut self, selector: String, params: Vec<Param>, body: Vec<IR>) {
+    pub fn add_handler_with_positions(
+        &mut self,
+        selector: Selector,
+        params: Vec<Param>,
+        body: Vec<IR>,
+        positions: Positions,
+    ) {
         self.handlers.insert(
-            selector.to_string(),
+            selector,
             Rc::new(Handler {
                 selector,
-                body,
+                // a var that's set right away, like an inlined handler's result being moved
+                // over its locals, doesn't outlive the instruction
+                has_vars: body.iter().enumerate().any(|(index, ir)| {
+                    matches!(ir, IR::Var(_)) && !matches!(body.get(index + 1), Some(IR::SetVar))
+                }),
+                body: body.into(),
                 params,
+                positions,
             }),
         );
     }
     pub fn add_native(&mut self, selector: &str, params: Vec<Param>, f: NativeFn) {
         let arity = params.len();
         self.add_handler(
-            selector.to_string(),
+            Selector::new(selector),
             params,
             vec![IR::SelfRef, IR::SendNative(f, arity)],
         );
     }
-    pub fn get(&self, selector: &str) -> Runtime<Rc<Handler>> {
-        match self.handlers.get(selector) {
+    pub fn get(&self, selector: Selector) -> Runtime<Rc<Handler>> {
+        match self.handlers.get(&selector) {
             Some(handler) => Ok(handler.clone()),
-            None => Err(RuntimeError::DoesNotUnderstand(selector.to_string())),
+            None => Err(RuntimeError::DoesNotUnderstand(selector)),
         }
     }
     pub fn rc(self) -> Rc<Class> {
@@ -355,9 +755,12 @@ impl Class {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Handler {
-    pub selector: String,
+    pub selector: Selector,
     pub params: Vec<Param>,
-    pub body: Vec<IR>,
+    pub body: Rc<[IR]>,
+    pub positions: Positions,
+    // vars are pointers to the frame's locals, which can outlive a send that ends the body
+    pub has_vars: bool,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -1,20 +1,68 @@
-use crate::grammar::{Source, Token, TokenWithSource};
+use crate::grammar::{Source, SourceContext, Token, TokenWithSource};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnknownChar(char),
+    UnterminatedString,
+    UnterminatedQuotedIdentifier,
+    InvalidEscape(String),
+    IntegerOverflow,
+    WithSource(Box<LexError>, Source),
+    InContext(Box<LexError>, SourceContext),
+}
+
+impl LexError {
+    pub fn with_source(self, source: Source) -> Self {
+        LexError::WithSource(Box::new(self), source)
+    }
+    pub fn in_context(self, context: &str) -> Self {
+        match self {
+            Self::WithSource(err, source) => Self::InContext(err, source.in_context(context)),
+            _ => self,
+        }
+    }
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownChar(ch) => write!(f, "unknown character {:?}", ch),
+            Self::UnterminatedString => write!(f, "unterminated string"),
+            Self::UnterminatedQuotedIdentifier => write!(f, "unterminated quoted identifier"),
+            Self::InvalidEscape(escape) => write!(f, "invalid escape sequence `\\{}`", escape),
+            Self::IntegerOverflow => write!(f, "integer literal is too large"),
+            Self::WithSource(err, _) => write!(f, "{}", err),
+            Self::InContext(err, context) => write!(f, "{}\n{}", err, context),
+        }
+    }
+}
+
+type Lex<T> = Result<T, LexError>;
 
 pub struct Lexer {
     chars: Vec<char>,
     index: usize,
+    // start index & brace depth of each open `${` in a string
+    interpolations: Vec<(usize, usize)>,
 }
 
 impl Lexer {
-    pub fn lex(str: &str) -> Vec<TokenWithSource> {
+    pub fn lex(str: &str) -> Lex<Vec<TokenWithSource>> {
         let mut out = vec![];
         let mut lexer = Lexer::new(str);
         loop {
+            lexer.skip_ignored();
             let start = lexer.index;
-            let token = lexer.next();
+            let token = lexer
+                .next()
+                .map_err(|err| err.with_source(Source::new(start, lexer.index - start)))?;
             let source = Source::new(start, lexer.index - start);
             if token == Token::EndOfInput {
-                return out;
+                if let Some((string_start, _)) = lexer.interpolations.pop() {
+                    let source = Source::new(string_start, lexer.index - string_start);
+                    return Err(LexError::UnterminatedString.with_source(source));
+                }
+                return Ok(out);
             }
             out.push(token.with_source(source));
         }
@@ -23,30 +71,36 @@ impl Lexer {
         Lexer {
             chars: str.chars().collect(),
             index: 0,
+            interpolations: vec![],
         }
     }
     fn peek(&self) -> char {
-        if self.index >= self.chars.len() {
+        if self.at_end() {
             return '\0';
         }
         self.chars[self.index]
     }
+    fn at_end(&self) -> bool {
+        self.index >= self.chars.len()
+    }
     fn advance(&mut self) {
-        if self.index == self.chars.len() {
-            panic!("unexpected end of input")
+        // callers only advance past a char they have peeked & matched, and peek returns
+        // '\0' at the end, which is only matched after checking at_end
+        if self.at_end() {
+            unreachable!("advanced past the end of input")
         }
         self.index += 1;
     }
-    fn next(&mut self) -> Token {
-        match self.peek() {
-            // these produce no tokens but call next recursively
-            '#' => self.comment(),
-            ' ' | '\t' | '\n' => self.whitespace(),
-            // actually produce values
-            '0'..='9' => self.number(),
+    fn next(&mut self) -> Lex<Token> {
+        let token = match self.peek() {
+            '0'..='9' => return self.number(),
             'a'..='z' | 'A'..='Z' => self.identifier_or_keyword(),
-            '"' => self.string(),
-            '_' => self.quoted_identifier(),
+            '"' => {
+                let start = self.index;
+                self.advance();
+                return self.string(start, false);
+            }
+            '_' => return self.quoted_identifier(),
             ':' => {
                 self.advance();
                 match self.peek() {
@@ -56,20 +110,38 @@ impl Lexer {
             }
             '(' => self.accept(Token::OpenParen),
             ')' => self.accept(Token::CloseParen),
-            '{' => self.accept(Token::OpenBrace),
-            '}' => self.accept(Token::CloseBrace),
+            '{' => {
+                if let Some((_, depth)) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.accept(Token::OpenBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some((start, 0)) => {
+                    let start = *start;
+                    self.interpolations.pop();
+                    self.advance();
+                    return self.string(start, true);
+                }
+                Some((_, depth)) => {
+                    *depth -= 1;
+                    self.accept(Token::CloseBrace)
+                }
+                None => self.accept(Token::CloseBrace),
+            },
             '[' => self.accept(Token::OpenBracket),
             ']' => self.accept(Token::CloseBracket),
             '?' => self.accept(Token::QuestionMark),
-            '\0' => Token::EndOfInput,
+            '\0' if self.at_end() => Token::EndOfInput,
             ch => {
                 if Token::is_operator(ch) {
                     self.operator()
                 } else {
-                    panic!("unknown char")
+                    return Err(LexError::UnknownChar(ch));
                 }
             }
-        }
+        };
+        Ok(token)
     }
 
     fn accept(&mut self, token: Token) -> Token {
@@ -77,32 +149,41 @@ impl Lexer {
         token
     }
 
-    fn comment(&mut self) -> Token {
+    // skip whitespace & comments, which produce no tokens
+    fn skip_ignored(&mut self) {
         loop {
             match self.peek() {
-                '\n' | '\0' => return self.next(),
-                _ => {
-                    self.advance();
+                '#' => {
+                    while !matches!(self.peek(), '\n' | '\0') {
+                        self.advance();
+                    }
                 }
+                ch if ch.is_whitespace() => self.advance(),
+                _ => return,
             }
         }
     }
-    fn whitespace(&mut self) -> Token {
-        loop {
-            if self.peek().is_whitespace() {
-                self.advance()
-            } else {
-                return self.next();
-            }
-        }
-    }
-    fn number(&mut self) -> Token {
-        let mut sum = 0;
+    fn number(&mut self) -> Lex<Token> {
+        let start = self.index;
+        let mut sum = Some(0i64);
         while let Some(digit) = self.peek().to_digit(10) {
             self.advance();
-            sum = sum * 10 + (digit as i64)
+            sum = sum
+                .and_then(|sum| sum.checked_mul(10))
+                .and_then(|sum| sum.checked_add(digit as i64));
         }
-        Token::Integer(sum)
+        // a `.` is only part of the number when followed by a digit, e.g. `1.5`
+        let is_fraction = self.peek() == '.'
+            && matches!(self.chars.get(self.index + 1), Some(ch) if ch.is_ascii_digit());
+        if is_fraction {
+            self.advance();
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+            let str = self.chars[start..self.index].iter().collect::<String>();
+            return Ok(Token::Float(str.parse().unwrap()));
+        }
+        sum.map(Token::Integer).ok_or(LexError::IntegerOverflow)
     }
     fn identifier_or_keyword(&mut self) -> Token {
         let mut str = String::new();
@@ -116,14 +197,16 @@ impl Lexer {
             }
         }
     }
-    fn quoted_identifier(&mut self) -> Token {
+    fn quoted_identifier(&mut self) -> Lex<Token> {
         let mut str = String::new();
         self.advance();
         loop {
             let ch = self.peek();
-            if ch == '_' {
+            if self.at_end() {
+                return Err(LexError::UnterminatedQuotedIdentifier);
+            } else if ch == '_' {
                 self.advance();
-                return Token::QuotedIdentifier(str);
+                return Ok(Token::QuotedIdentifier(str));
             } else {
                 self.advance();
                 str.push(ch);
@@ -142,18 +225,154 @@ impl Lexer {
             }
         }
     }
-    fn string(&mut self) -> Token {
+    // lexes a string up to its closing quote or the next `${`.
+    // continued is true when resuming a string after an interpolated expression
+    fn string(&mut self, start: usize, continued: bool) -> Lex<Token> {
         let mut str = String::new();
-        self.advance();
         loop {
             let ch = self.peek();
-            if ch == '"' {
+            if self.at_end() {
+                return Err(LexError::UnterminatedString);
+            } else if ch == '"' {
+                self.advance();
+                return Ok(if continued {
+                    Token::StringEnd(str)
+                } else {
+                    Token::String(str)
+                });
+            } else if ch == '$' && self.chars.get(self.index + 1) == Some(&'{') {
                 self.advance();
-                return Token::String(str);
+                self.advance();
+                self.interpolations.push((start, 0));
+                return Ok(if continued {
+                    Token::StringMiddle(str)
+                } else {
+                    Token::StringStart(str)
+                });
+            } else if ch == '\\' {
+                self.advance();
+                str.push(self.escape()?);
             } else {
                 self.advance();
                 str.push(ch);
             }
         }
     }
+    fn escape(&mut self) -> Lex<char> {
+        let ch = self.peek();
+        if self.at_end() {
+            return Err(LexError::UnterminatedString);
+        }
+        self.advance();
+        match ch {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            '$' => Ok('$'),
+            'u' => {
+                let mut code = String::new();
+                if self.peek() == '{' {
+                    self.advance();
+                    while self.peek().is_ascii_hexdigit() {
+                        code.push(self.peek());
+                        self.advance();
+                    }
+                    if self.peek() == '}' {
+                        self.advance();
+                        if let Some(ch) =
+                            u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+                        {
+                            return Ok(ch);
+                        }
+                    }
+                }
+                Err(LexError::InvalidEscape(format!("u{{{}}}", code)))
+            }
+            ch => Err(LexError::InvalidEscape(ch.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_err(code: &str, expected: LexError, source: Source) {
+        assert_eq!(Lexer::lex(code), Err(expected.with_source(source)))
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            Lexer::lex("let x := 1 # comment"),
+            Ok(vec![
+                Token::Let.with_source(Source::new(0, 3)),
+                Token::Identifier("x".to_string()).with_source(Source::new(4, 1)),
+                Token::ColonEquals.with_source(Source::new(6, 2)),
+                Token::Integer(1).with_source(Source::new(9, 1)),
+            ])
+        );
+        assert_eq!(
+            Lexer::lex("1.5 2.x"),
+            Ok(vec![
+                Token::Float(1.5).with_source(Source::new(0, 3)),
+                Token::Integer(2).with_source(Source::new(4, 1)),
+                Token::Operator(".".to_string()).with_source(Source::new(5, 1)),
+                Token::Identifier("x".to_string()).with_source(Source::new(6, 1)),
+            ])
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let tokens = |code| {
+            Lexer::lex(code)
+                .unwrap()
+                .into_iter()
+                .map(|t| t.token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tokens(r#""a\n\t\"\\\$\u{1F600}""#),
+            vec![Token::String("a\n\t\"\\$\u{1F600}".to_string())]
+        );
+        assert_eq!(
+            tokens(r#""a${x{y}}b${"c${1}"}""#),
+            vec![
+                Token::StringStart("a".to_string()),
+                Token::Identifier("x".to_string()),
+                Token::OpenBrace,
+                Token::Identifier("y".to_string()),
+                Token::CloseBrace,
+                Token::StringMiddle("b".to_string()),
+                Token::StringStart("c".to_string()),
+                Token::Integer(1),
+                Token::StringEnd("".to_string()),
+                Token::StringEnd("".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_err("1 ; 2", LexError::UnknownChar(';'), Source::new(2, 0));
+        assert_err("x \"abc", LexError::UnterminatedString, Source::new(2, 4));
+        assert_err(
+            "_abc",
+            LexError::UnterminatedQuotedIdentifier,
+            Source::new(0, 4),
+        );
+        assert_err(
+            "\"a\\qb\"",
+            LexError::InvalidEscape("q".to_string()),
+            Source::new(0, 4),
+        );
+        assert_err("\"a ${x", LexError::UnterminatedString, Source::new(0, 6));
+        assert_err(
+            "99999999999999999999",
+            LexError::IntegerOverflow,
+            Source::new(0, 20),
+        );
+    }
 }
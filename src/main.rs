@@ -1,71 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
 use compiler::CompilerFlags;
+use grammar::SourceFile;
 
 mod ast;
+mod bigint;
 mod compiler;
 mod grammar;
 mod ir;
 mod lexer;
 mod native;
+mod optimizer;
 mod parser;
+mod repl;
 mod runtime;
+mod test_runner;
 
-const COMPILER_FLAGS: CompilerFlags = CompilerFlags {
+pub const COMPILER_FLAGS: CompilerFlags = CompilerFlags {
     // allow_inline: false,
     allow_inline: true,
+    inline_budget: 8,
+    passes: optimizer::ALL_PASSES,
 };
 
-fn compile_module(code: &str) -> Vec<ir::IR> {
-    let tokens = lexer::Lexer::lex(code);
+fn compile_module(file: &str, code: &str) -> ir::Program {
+    let tokens = lexer::Lexer::lex(code).unwrap();
     let ast = parser::Parser::parse(tokens).unwrap();
-    compiler::Compiler::new(COMPILER_FLAGS).module(ast).unwrap()
+    compiler::Compiler::new(COMPILER_FLAGS)
+        .with_file(SourceFile::new(file, code))
+        .module(ast)
+        .unwrap()
 }
 
 fn build_stdlib() -> runtime::ModuleLoader {
     let mut modules = runtime::ModuleLoader::new();
     modules.add_ready("native", native::native_module());
-    modules.add_init("core", compile_module(include_str!("./stdlib/core.gob")));
-    modules.add_init("core/ord", compile_module(include_str!("./stdlib/ord.gob")));
-    modules.add_init(
-        "core/option",
-        compile_module(include_str!("./stdlib/option.gob")),
-    );
-    modules.add_init(
-        "core/result",
-        compile_module(include_str!("./stdlib/result.gob")),
-    );
-    modules.add_init(
-        "core/control",
-        compile_module(include_str!("./stdlib/control.gob")),
-    );
-    modules.add_init(
-        "core/iter",
-        compile_module(include_str!("./stdlib/iter.gob")),
-    );
-    modules.add_init(
-        "core/sortable",
-        compile_module(include_str!("./stdlib/sortable.gob")),
-    );
-    modules.add_init(
-        "core/slice",
-        compile_module(include_str!("./stdlib/slice.gob")),
-    );
-    modules.add_init(
-        "core/panic",
-        compile_module(include_str!("./stdlib/panic.gob")),
-    );
-    modules.add_init(
-        "core/range",
-        compile_module(include_str!("./stdlib/range.gob")),
-    );
-    modules.add_init(
-        "core/hash",
-        compile_module(include_str!("./stdlib/hash.gob")),
-    );
-    modules.add_init("parse", compile_module(include_str!("./stdlib/parse.gob")));
-    modules.add_init(
-        "bitset",
-        compile_module(include_str!("./stdlib/bitset.gob")),
-    );
+    let stdlib = [
+        ("core", "stdlib/core.gob", include_str!("./stdlib/core.gob")),
+        (
+            "core/ord",
+            "stdlib/ord.gob",
+            include_str!("./stdlib/ord.gob"),
+        ),
+        (
+            "core/option",
+            "stdlib/option.gob",
+            include_str!("./stdlib/option.gob"),
+        ),
+        (
+            "core/result",
+            "stdlib/result.gob",
+            include_str!("./stdlib/result.gob"),
+        ),
+        (
+            "core/control",
+            "stdlib/control.gob",
+            include_str!("./stdlib/control.gob"),
+        ),
+        (
+            "core/iter",
+            "stdlib/iter.gob",
+            include_str!("./stdlib/iter.gob"),
+        ),
+        (
+            "core/sortable",
+            "stdlib/sortable.gob",
+            include_str!("./stdlib/sortable.gob"),
+        ),
+        (
+            "core/slice",
+            "stdlib/slice.gob",
+            include_str!("./stdlib/slice.gob"),
+        ),
+        (
+            "core/panic",
+            "stdlib/panic.gob",
+            include_str!("./stdlib/panic.gob"),
+        ),
+        (
+            "core/range",
+            "stdlib/range.gob",
+            include_str!("./stdlib/range.gob"),
+        ),
+        (
+            "core/hash",
+            "stdlib/hash.gob",
+            include_str!("./stdlib/hash.gob"),
+        ),
+        (
+            "parse",
+            "stdlib/parse.gob",
+            include_str!("./stdlib/parse.gob"),
+        ),
+        (
+            "bitset",
+            "stdlib/bitset.gob",
+            include_str!("./stdlib/bitset.gob"),
+        ),
+    ];
+    for (name, file, code) in stdlib {
+        modules.add_init(name, compile_module(file, code));
+    }
     modules
 }
 
@@ -73,53 +109,573 @@ thread_local! {
     static STDLIB : runtime::ModuleLoader = build_stdlib()
 }
 
-fn run(code: &str) {
-    let tokens = lexer::Lexer::lex(code);
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    Io(String, std::io::Error),
+    Lex(lexer::LexError),
+    Parse(parser::ParseError),
+    Compile(compiler::CompileError),
+    Runtime(runtime::RuntimeError),
+    TestsFailed(usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::Io(path, err) => write!(f, "cannot read {}: {}", path, err),
+            Error::Lex(err) => write!(f, "syntax error: {}", err),
+            Error::Parse(err) => write!(f, "parse error: {}", err),
+            Error::Compile(err) => write!(f, "compile error: {}", err),
+            Error::Runtime(err) => write!(f, "runtime error: {}", err),
+            Error::TestsFailed(count) => write!(f, "{} test file(s) failed", count),
+        }
+    }
+}
+
+fn compile_file_module(path: &Path, code: &str) -> Result<ir::Program, String> {
+    let tokens =
+        lexer::Lexer::lex(code).map_err(|err| Error::Lex(err.in_context(code)).to_string())?;
     let ast = parser::Parser::parse(tokens)
-        .map_err(|err| err.in_context(code))
-        .unwrap();
-    let ir = compiler::Compiler::new(COMPILER_FLAGS)
-        .program(ast)
-        .unwrap();
+        .map_err(|err| Error::Parse(err.in_context(code)).to_string())?;
+    compiler::Compiler::new(COMPILER_FLAGS)
+        .with_file(SourceFile::new(&path.display().to_string(), code))
+        .with_module_path(path)
+        .module(ast)
+        .map_err(|err| Error::Compile(err.in_context(code)).to_string())
+}
+
+// stdlib, plus .gob files relative to the entry path or in the directories in GOBLIN_PATH
+pub fn load_modules(entry: Option<&Path>) -> runtime::ModuleLoader {
     let mut modules = STDLIB.with(|m| m.clone());
-    let result = runtime::Interpreter::program(ir, &mut modules);
-    match result {
-        Ok(value) => {
-            println!("{:?}", value);
+    let mut roots = vec![match entry.and_then(|path| path.parent()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from("."),
+    }];
+    if let Some(paths) = std::env::var_os("GOBLIN_PATH") {
+        roots.extend(std::env::split_paths(&paths));
+    }
+    modules.add_file_resolver(compile_file_module, roots);
+    if let Some(path) = entry {
+        modules.set_entry_path(path);
+    }
+    modules
+}
+
+// file is the name used for source positions
+fn eval(file: &str, code: &str, modules: &mut runtime::ModuleLoader) -> Result<ir::Value, Error> {
+    eval_with_flags(file, code, modules, COMPILER_FLAGS)
+}
+
+fn eval_with_flags(
+    file: &str,
+    code: &str,
+    modules: &mut runtime::ModuleLoader,
+    flags: CompilerFlags,
+) -> Result<ir::Value, Error> {
+    let ir = compile_program(file, code, flags)?;
+    runtime::Interpreter::program(ir, modules, runtime::Limits::default()).map_err(Error::Runtime)
+}
+
+fn compile_program(file: &str, code: &str, flags: CompilerFlags) -> Result<ir::Program, Error> {
+    let tokens = lexer::Lexer::lex(code).map_err(|err| Error::Lex(err.in_context(code)))?;
+    let ast = parser::Parser::parse(tokens).map_err(|err| Error::Parse(err.in_context(code)))?;
+    compiler::Compiler::new(flags)
+        .with_file(SourceFile::new(file, code))
+        .program(ast)
+        .map_err(|err| Error::Compile(err.in_context(code)))
+}
+
+// Ctrl-C interrupts the code being evaluated instead of exiting
+pub fn interrupt_on_ctrl_c(interrupt: runtime::Interrupt) {
+    // only fails if a handler is already set, which leaves Ctrl-C's default behaviour
+    ctrlc::set_handler(move || interrupt.interrupt()).ok();
+}
+
+fn run_file(path: &str, args: &[String]) -> Result<(), Error> {
+    let code = std::fs::read_to_string(path).map_err(|err| Error::Io(path.to_string(), err))?;
+    native::set_process_args(args.to_vec());
+    let ir = compile_program(path, &code, COMPILER_FLAGS)?;
+    let interrupt = runtime::Interrupt::new();
+    interrupt_on_ctrl_c(interrupt.clone());
+    let mut modules = load_modules(Some(Path::new(path)));
+    runtime::Interpreter::new(&mut modules, runtime::Limits::default())
+        .with_interrupt(interrupt)
+        .run_program(ir)
+        .map_err(Error::Runtime)?;
+    Ok(())
+}
+
+struct BenchResult {
+    path: PathBuf,
+    // the fastest run
+    time: Duration,
+    // inline cache hits & misses over all runs
+    cache: ir::CacheStats,
+}
+
+fn bench_files(paths: &[PathBuf], runs: usize) -> Result<Vec<BenchResult>, Error> {
+    let mut results = vec![];
+    for path in paths {
+        let file = path.display().to_string();
+        let code = std::fs::read_to_string(path).map_err(|err| Error::Io(file.clone(), err))?;
+        let mut time = Duration::MAX;
+        ir::reset_cache_stats();
+        for _ in 0..runs {
+            let start = Instant::now();
+            eval(&file, &code, &mut load_modules(Some(path)))?;
+            time = time.min(start.elapsed());
         }
-        Err(error) => {
-            println!("Error: {:#?}", error);
-            panic!("error");
+        results.push(BenchResult {
+            path: path.clone(),
+            time,
+            cache: ir::cache_stats(),
+        });
+    }
+    Ok(results)
+}
+
+fn bench(args: &[String]) -> Result<(), Error> {
+    let mut paths = vec![];
+    let mut runs = 3;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--runs" | "-n" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) if value > 0 => runs = value,
+                _ => return Err(Error::Usage("expected a number after --runs".to_string())),
+            },
+            _ => paths.push(PathBuf::from(arg)),
         }
     }
+    if paths.is_empty() {
+        return Err(Error::Usage(USAGE.to_string()));
+    }
+    let results = bench_files(&paths, runs)?;
+    println!();
+    println!("{:>12}  {:>14}  path", "time", "cache hits");
+    for result in results {
+        println!(
+            "{:>10.1}ms  {:>13.1}%  {}",
+            result.time.as_secs_f64() * 1000.0,
+            result.cache.hit_rate() * 100.0,
+            result.path.display()
+        );
+    }
+    Ok(())
 }
 
-fn main() {
-    let stdin = std::io::stdin();
+fn run_stdin() -> Result<(), Error> {
     let mut input = String::new();
-    loop {
-        match stdin.read_line(&mut input) {
-            Ok(0) => {
-                run(&input);
-                return;
-            }
-            Ok(_) => {}
-            Err(err) => {
-                panic!("{:?}", err)
-            }
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+        .map_err(|err| Error::Io("stdin".to_string(), err))?;
+    let value = eval("<stdin>", &input, &mut load_modules(None))?;
+    println!("{}", value.debug());
+    Ok(())
+}
+
+const USAGE: &str = "usage:
+  goblin_rs                        run a program from stdin
+  goblin_rs run <path> [args...]   run a program from a file
+  goblin_rs repl                   start an interactive session
+  goblin_rs test [paths...]        run *.test.gob files in paths (default: current directory)
+    -f, --filter <text>            only run test files whose path contains text
+  goblin_rs bench <paths...>       time programs, reporting the fastest run of each
+                                   and the hit rate of the message send caches
+    -n, --runs <count>             runs per program (default: 3)";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.first().map(|s| s.as_str()) {
+        None => run_stdin(),
+        Some("run") => match args.get(1) {
+            Some(path) => run_file(path, &args[2..]),
+            None => Err(Error::Usage(USAGE.to_string())),
+        },
+        Some("repl") => repl::repl(),
+        Some("test") => test_runner::test(&args[1..]),
+        Some("bench") => bench(&args[1..]),
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            Ok(())
         }
+        Some(command) => Err(Error::Usage(format!(
+            "unknown command \"{}\"\n{}",
+            command, USAGE
+        ))),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(match error {
+            Error::Usage(_) => 2,
+            _ => 1,
+        });
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::run;
+    use crate::{
+        bench_files, compiler::CompilerFlags, eval, eval_with_flags, ir::Selector, load_modules,
+        runtime::RuntimeError, Error, COMPILER_FLAGS, STDLIB,
+    };
+
+    fn eval_stdlib(code: &str) -> Result<crate::ir::Value, Error> {
+        eval("<test>", code, &mut STDLIB.with(|m| m.clone()))
+    }
+
+    fn run(code: &str) {
+        match eval_stdlib(code) {
+            Ok(value) => {
+                println!("{:?}", value);
+            }
+            Err(error) => {
+                println!("Error: {}", error);
+                panic!("error");
+            }
+        }
+    }
 
     #[test]
     fn empty_program() {
         run("")
     }
 
+    #[test]
+    fn errors() {
+        assert!(matches!(eval_stdlib("let x := \"abc"), Err(Error::Lex(_))));
+        assert!(matches!(eval_stdlib("let x :="), Err(Error::Parse(_))));
+        assert!(matches!(eval_stdlib("x"), Err(Error::Compile(_))));
+        assert!(matches!(eval_stdlib("1{foo}"), Err(Error::Runtime(_))));
+    }
+
+    #[test]
+    fn string_interpolation() {
+        assert_eq!(
+            eval_stdlib("let x := 2\n\"total:\\t${x + 1}${\"!\"}\\n\"")
+                .unwrap()
+                .debug(),
+            "\"total:\\t3!\\n\""
+        );
+    }
+
+    #[test]
+    fn string_debug() {
+        let code = r#""say \"hi\"\n\\\t\${x} $ \u{7}""#;
+        let value = eval_stdlib(code).unwrap();
+        assert_eq!(value.debug(), code);
+        assert_eq!(eval_stdlib(&value.debug()).unwrap(), value);
+    }
+
+    #[test]
+    fn parse_error_positions() {
+        let err = eval_stdlib("let x := 1 +").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "parse error: expected expr\nat line 1, column 13:\n  t x := 1 +\n            ^"
+        );
+        let err = eval_stdlib("let x := 1 +\n\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "parse error: expected expr\nat line 1, column 13:\n  t x := 1 +\n            ^"
+        );
+    }
+
+    #[test]
+    fn compile_error_positions() {
+        let err = eval_stdlib("let x := 1\nlet y := x + z").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "compile error: unknown identifier `z`\nat line 2, column 14:\n   y := x + z\n            ^"
+        );
+        let err = eval_stdlib("var [x: a] := [x: 1]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "compile error: invalid binding for `var`\nat line 1, column 5:\n  var [x: a] := \n      ^"
+        );
+    }
+
+    #[test]
+    fn if_conditions() {
+        // conditions must be bools, whether or not a branch binds names
+        for code in [
+            "let x := 1\nif x then 1 else 2 end",
+            "let x := 1\nif x then\n  let y := 1\n  y\nelse 2 end",
+        ] {
+            assert_eq!(
+                eval_stdlib(code).unwrap_err().to_string(),
+                "runtime error: expected Bool, received Int\n  at <test>:2:4 <root>"
+            );
+        }
+        assert_eq!(
+            eval_stdlib("if true then\n  let y := 1\n  y + 1\nelse 2 end").unwrap(),
+            crate::ir::Value::Integer(2)
+        );
+        // a branch's locals go above the values already on the stack, & are dropped after it
+        for (code, expected) in [
+            (
+                "let a := 5\nlet b := if a = 5 then\n  let c := a + 1\n  c\nelse 0 end\nlet d := 1\na + b + d",
+                12,
+            ),
+            (
+                "let a := 5\n[x: a y: if a = 5 then\n  let y := a * 2\n  var z := y\n  set z := z + 1\n  z\nelse 0 end]{y}",
+                11,
+            ),
+        ] {
+            assert_eq!(eval_stdlib(code).unwrap(), crate::ir::Value::Integer(expected));
+        }
+        assert!(matches!(
+            eval_stdlib("if true then\n  export let x := 1\n  x\nelse 2 end"),
+            Err(Error::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let base_error = |code: &str| match eval_stdlib(code) {
+            Err(Error::Runtime(err)) => err.base_error(),
+            _ => panic!("expected runtime error"),
+        };
+        // the native array is shared between evals, so its length varies
+        let array = "import native := \"native\"\nlet arr := native{new Array}\narr{push: 1}\n";
+        assert!(matches!(
+            base_error(&format!("{}arr{{at: 1000}}", array)),
+            RuntimeError::IndexOutOfRange(1000, _)
+        ));
+        assert!(matches!(
+            base_error(&format!("{}arr{{at: -1 value: 2}}", array)),
+            RuntimeError::IndexOutOfRange(-1, _)
+        ));
+        assert!(matches!(
+            base_error(&format!("{}arr{{from: 0 to: 1000}}", array)),
+            RuntimeError::IndexOutOfRange(1000, _)
+        ));
+        assert_eq!(
+            base_error(&format!("{}arr{{at: \"x\"}}", array)),
+            RuntimeError::TypeMismatch("Int".to_string(), "String".to_string())
+        );
+        assert_eq!(
+            base_error("use{logger}"),
+            RuntimeError::NoProvider("logger".to_string())
+        );
+        assert_eq!(base_error("1 // 0"), RuntimeError::DivisionByZero);
+        assert_eq!(base_error("1 % 0"), RuntimeError::DivisionByZero);
+        assert_eq!(
+            base_error("import [_String_] := \"core\"\nString{from char code: -1}"),
+            RuntimeError::InvalidCharCode(-1)
+        );
+        assert_eq!(
+            base_error("import [_Panic_] := \"core\"\nPanic{: 1}"),
+            RuntimeError::Panic(crate::ir::Value::Integer(1))
+        );
+        assert_eq!(
+            base_error(
+                "import [_Control_ _Panic_] := \"core\"\nControl{ensure: {} 1 do: {} Panic{: 2}}"
+            ),
+            RuntimeError::Panic(crate::ir::Value::Integer(2))
+        );
+        assert!(matches!(
+            base_error("[on {loop} 1 + self{loop}]{loop}"),
+            RuntimeError::StackOverflow(_)
+        ));
+        // only panics are caught
+        assert_eq!(
+            base_error("import [_Control_] := \"core\"\nControl{try: {} 1 // 0}"),
+            RuntimeError::DivisionByZero
+        );
+        assert_eq!(
+            base_error("import [_Control_] := \"core\"\nControl{try: {} 1{foo}}"),
+            RuntimeError::DoesNotUnderstand(crate::ir::Selector::new("foo"))
+        );
+        // `?` only catches the wrapped send not being understood
+        assert_eq!(
+            base_error("[on {foo} 1{bar}]{foo} ? 2"),
+            RuntimeError::DoesNotUnderstand(crate::ir::Selector::new("bar"))
+        );
+        assert!(matches!(
+            eval_stdlib("1 // 0"),
+            Err(Error::Runtime(RuntimeError::WithStackTrace(_, _)))
+        ));
+    }
+
+    #[test]
+    fn assertion_failure() {
+        let err = match eval_stdlib(
+            "import [_Assert_] := \"core\"\nAssert{expected: [x: 1 y: 2] received: [x: 1 y: 3]}",
+        ) {
+            Err(Error::Runtime(err)) => err.base_error(),
+            _ => panic!("expected runtime error"),
+        };
+        assert_eq!(
+            err.to_string(),
+            "assertion failed at <test>:2:7
+  expected: [x: 1 y: 2]
+  received: [x: 1 y: 3]
+    y: expected 2, received 3"
+        );
+
+        // an assertion that ends a handler reports its own line, not the handler's caller's
+        let err = match eval_stdlib(
+            "import [_Assert_] := \"core\"\nlet obj := [\n  on {check}\n    Assert{expected: 1 received: 2}\n]\nobj{check}",
+        ) {
+            Err(Error::Runtime(err)) => err.base_error(),
+            _ => panic!("expected runtime error"),
+        };
+        assert!(
+            err.to_string()
+                .starts_with("assertion failed at <test>:4:11\n"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn stack_trace_positions() {
+        let code = "let obj := [\n  on {foo} 1{bar}\n]\nobj{foo}";
+        let err = match eval_stdlib(code) {
+            Err(Error::Runtime(err)) => err,
+            _ => panic!("expected runtime error"),
+        };
+        assert_eq!(
+            err.to_string(),
+            "does not understand message {bar}
+  at <test>:2:13 {foo}
+  at <test>:4:4 <root>"
+        );
+    }
+
+    #[test]
+    fn inlining() {
+        let eval_with = |code: &str, allow_inline: bool| {
+            let flags = CompilerFlags {
+                allow_inline,
+                ..COMPILER_FLAGS
+            };
+            eval_with_flags("<test>", code, &mut STDLIB.with(|m| m.clone()), flags)
+                .map_err(|err| err.to_string())
+        };
+        let point = "let Point := [\n  on {x: x y: y} [x: x y: y]\n]\n";
+        let cases = [
+            "let p := Point{x: 1 y: 2}\np{x} + p{y}",
+            "let p := Point{x: 1 y: 2}\np{x: 3}{x} * p{y}",
+            "let p := Point{x: 1 y: 2}\np = Point{x: 1 y: 2}",
+            "let x := 3\n10 - x < (2 * x)",
+            "let s := \"abc\"\ns{length} + 1{to String}{length}",
+            "true != false",
+            "var x := 1\nset x := 2 * x\nx",
+        ];
+        for case in cases {
+            let code = format!("{}{}", point, case);
+            let inlined = eval_with(&code, true);
+            assert!(inlined.is_ok(), "{}: {:?}", case, inlined);
+            assert_eq!(inlined, eval_with(&code, false), "{}", case);
+        }
+        // handlers with locals, and sends to self
+        let cases = [
+            "let obj := [\n  on {double: x}\n    let y := x + x\n    y\n]\n1 + obj{double: 2} * 3",
+            "let obj := [\n  on {sum: a b: b}\n    let c := a + b\n    let d := c * 2\n    d - c\n]\nobj{sum: 1 b: obj{sum: 2 b: 3}}",
+            "let obj := [\n  on {a} 1\n  on {b} self{a} + self{a}\n]\nobj{b}",
+            "let obj := [\n  on {a: x}\n    let y := x * 2\n    y + 1\n  on {b: x} [sum: x other: self{a: x}]\n]\nobj{b: 4}{other}",
+            "let p := Point{x: 1 y: 2}\n[x: 1 y: p{x: 3}{x}]{y}",
+        ];
+        for case in cases {
+            let code = format!("{}{}", point, case);
+            let inlined = eval_with(&code, true);
+            assert!(inlined.is_ok(), "{}: {:?}", case, inlined);
+            assert_eq!(inlined, eval_with(&code, false), "{}", case);
+        }
+        let program = crate::compile_program("<test>", cases[0], COMPILER_FLAGS).unwrap();
+        let double = Selector::new("double:");
+        assert!(
+            !program
+                .body
+                .iter()
+                .any(|ir| matches!(ir, crate::ir::IR::Send(selector, ..) if *selector == double)),
+            "{:?}",
+            program.body
+        );
+        // errors from inlined handlers have the same trace as from sends, and do args are still
+        // rejected by value params
+        let errors = [
+            "1 // 0",
+            "2 + \"a\"",
+            "let p := Point{x: 1 y: 2}\np{z}",
+            "let obj := [\n  on {a: x}\n    let y := x\n    y{foo}\n]\n1 + obj{a: 2}",
+            "let obj := [on {id: x} x]\nobj{id: {} 1}",
+            "let obj := [on {id: x} x]\n[on {call: do f} obj{id: f}]{call: {} 1}",
+        ];
+        for case in errors {
+            let code = format!("{}{}", point, case);
+            let inlined = eval_with(&code, true);
+            assert!(inlined.is_err(), "{}: {:?}", case, inlined);
+            assert_eq!(inlined, eval_with(&code, false), "{}", case);
+        }
+
+        // moving an inlined handler's result over its locals doesn't stop tail calls
+        let code = "let obj := [\n  on {double: x}\n    let y := x + x\n    y\n  on {loop: n}\n    if n = 0 then 0 else self{loop: n - 1 + self{double: 0}} end\n]\nobj{loop: 200000}";
+        assert_eq!(eval_with(code, true), Ok(crate::ir::Value::Integer(0)));
+    }
+
+    #[test]
+    fn file_modules() {
+        let dir = std::env::temp_dir().join("goblin_file_modules");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/double.gob"),
+            "export let double := [{: x} x * 2]",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("grid.gob"),
+            "import [_double_] := \"lib/double\"\nexport let size := double{: 5}",
+        )
+        .unwrap();
+        let entry = dir.join("main.gob");
+        let result = eval(
+            "<test>",
+            "import [_size_] := \"./grid\"\nsize",
+            &mut load_modules(Some(&entry)),
+        );
+        assert_eq!(result.unwrap(), crate::ir::Value::Integer(10));
+        assert!(matches!(
+            eval(
+                "<test>",
+                "import [_x_] := \"./missing\"",
+                &mut load_modules(Some(&entry))
+            ),
+            Err(Error::Runtime(_))
+        ));
+
+        std::fs::write(dir.join("bad.gob"), "export let x := 1{foo}").unwrap();
+        let mut modules = load_modules(Some(&entry));
+        for _ in 0..2 {
+            let err = match eval("<test>", "import [_x_] := \"./bad\"", &mut modules) {
+                Err(Error::Runtime(err)) => err.base_error(),
+                _ => panic!("expected runtime error"),
+            };
+            assert_eq!(err, RuntimeError::DoesNotUnderstand(Selector::new("foo")));
+        }
+
+        // imports in handlers run after the module has loaded, & are still relative to it
+        std::fs::write(dir.join("b.gob"), "export let name := \"root b\"").unwrap();
+        std::fs::write(dir.join("lib/b.gob"), "export let name := \"lib b\"").unwrap();
+        std::fs::write(
+            dir.join("lib/a.gob"),
+            "export let A := [\n  on {go}\n    import [_name_] := \"./b\"\n    name\n]",
+        )
+        .unwrap();
+        let result = eval(
+            "<test>",
+            "import [_A_] := \"./lib/a\"\nA{go}",
+            &mut load_modules(Some(&entry)),
+        );
+        assert_eq!(
+            result.unwrap(),
+            crate::ir::Value::String(std::rc::Rc::new("lib b".to_string()))
+        );
+    }
+
     #[test]
     fn syntax() {
         run(include_str!("./syntax.gob"))
@@ -137,6 +693,26 @@ mod test {
         run(include_str!("./stdlib/option.test.gob"));
     }
 
+    #[test]
+    fn floats() {
+        run(include_str!("./stdlib/float.test.gob"));
+    }
+
+    #[test]
+    fn bigints() {
+        run(include_str!("./stdlib/bigint.test.gob"));
+    }
+
+    #[test]
+    fn provide() {
+        run(include_str!("./stdlib/provide.test.gob"));
+    }
+
+    #[test]
+    fn tail_calls() {
+        run(include_str!("./stdlib/tail_call.test.gob"));
+    }
+
     #[test]
     fn strings() {
         run(include_str!("./stdlib/string.test.gob"));
@@ -196,6 +772,18 @@ mod test {
         run(include_str!("./stdlib/range.test.gob"));
     }
 
+    #[test]
+    fn bench() {
+        let path = std::env::temp_dir().join("goblin_bench.gob");
+        std::fs::write(&path, "let x := 1 + 2").unwrap();
+        let results = bench_files(std::slice::from_ref(&path), 2).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, path);
+
+        std::fs::write(&path, "1{foo}").unwrap();
+        assert!(matches!(bench_files(&[path], 2), Err(Error::Runtime(_))));
+    }
+
     #[test]
     #[ignore]
     fn day_1() {
@@ -1,4 +1,5 @@
 use std::{
+    cell::RefCell,
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
     ops::Deref,
@@ -7,20 +8,124 @@ use std::{
 };
 
 use crate::{
-    ir::{Class, Object, Param, Value, IR},
-    runtime::{Runtime, RuntimeError},
+    bigint::BigInt,
+    ir::{Class, Object, Param, Selector, Value, IR},
+    runtime::{Interpreter, Runtime, RuntimeError},
 };
 
 fn expected<T>(t: &str) -> Runtime<T> {
     Err(RuntimeError::ExpectedType(t.to_string()))
 }
 
+fn panic<T>(message: &str) -> Runtime<T> {
+    Err(RuntimeError::Panic(Value::String(Rc::new(
+        message.to_string(),
+    ))))
+}
+
+// ints, bigints and floats, as a float
+fn as_number(value: &Value) -> Runtime<f64> {
+    match value {
+        Value::Integer(value) => Ok(*value as f64),
+        Value::Bigint(value) => Ok(value.to_f64()),
+        Value::Float(value) => Ok(*value),
+        _ => expected("number"),
+    }
+}
+
+// ints and bigints, as a bigint
+fn as_bigint(value: &Value) -> Runtime<BigInt> {
+    match value {
+        Value::Integer(value) => Ok(BigInt::from(*value)),
+        Value::Bigint(value) => Ok(value.as_ref().clone()),
+        _ => expected("integer"),
+    }
+}
+
+fn as_shift(value: &Value) -> Runtime<usize> {
+    let bits = match value {
+        Value::Integer(value) => usize::try_from(*value).ok(),
+        Value::Bigint(value) => value.to_usize(),
+        _ => None,
+    };
+    bits.map_or_else(|| expected("non-negative integer"), Ok)
+}
+
+// bigints only hold values that don't fit in an int, so equal integers are equal values
+fn big(value: BigInt) -> Value {
+    match value.to_i64() {
+        Some(value) => Value::Integer(value),
+        None => Value::Bigint(Rc::new(value)),
+    }
+}
+
+// `//` & `%` round toward negative infinity for every kind of number, so the remainder has
+// the sign of the divisor & `a // b * b + a % b = a`
+fn int_div(left: i64, right: i64) -> Option<i64> {
+    let quotient = left.checked_div(right)?;
+    if left % right != 0 && (left < 0) != (right < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+fn int_mod(left: i64, right: i64) -> i64 {
+    let rem = left.wrapping_rem(right);
+    if rem != 0 && (rem < 0) != (right < 0) {
+        rem + right
+    } else {
+        rem
+    }
+}
+
+fn float_div(left: f64, right: f64) -> f64 {
+    (left / right).floor()
+}
+
+fn float_mod(left: f64, right: f64) -> f64 {
+    let rem = left % right;
+    if rem != 0.0 && (rem < 0.0) != (right < 0.0) {
+        rem + right
+    } else {
+        rem
+    }
+}
+
+fn big_div(left: &BigInt, right: &BigInt) -> Runtime<Value> {
+    match left.div_floor(right) {
+        Some(value) => Ok(big(value)),
+        None => Err(RuntimeError::DivisionByZero),
+    }
+}
+
+fn big_mod(left: &BigInt, right: &BigInt) -> Runtime<Value> {
+    match left.mod_floor(right) {
+        Some(value) => Ok(big(value)),
+        None => Err(RuntimeError::DivisionByZero),
+    }
+}
+
+fn array_index(index: i64, length: usize) -> Runtime<usize> {
+    match usize::try_from(index) {
+        Ok(i) if i < length => Ok(i),
+        _ => Err(RuntimeError::IndexOutOfRange(index, length)),
+    }
+}
+
+fn char_at(str: &str, index: i64) -> Runtime<char> {
+    usize::try_from(index)
+        .ok()
+        .and_then(|i| str.chars().nth(i))
+        .ok_or_else(|| RuntimeError::IndexOutOfRange(index, str.chars().count()))
+}
+
 fn at_wrap(length: Value, args: Vec<Value>) -> Runtime<Value> {
-    let length = length.as_int();
+    let length = length.as_int()?;
     match &args[0] {
         Value::Integer(at) => {
             if length == 0 || *at < -length || *at >= length {
-                return Err(RuntimeError::Panic("index out of range".to_string()));
+                return Err(RuntimeError::IndexOutOfRange(*at, length as usize));
             }
             Ok(Value::Integer(at.rem_euclid(length)))
         }
@@ -36,28 +141,28 @@ fn build_bool_class() -> Rc<Class> {
         |target, mut args| {
             let t = args.pop().unwrap();
             let f = args.pop().unwrap();
-            if target.as_bool() {
+            if target.as_bool()? {
                 Ok(t)
             } else {
                 Ok(f)
             }
         },
     );
-    class.add_native("!", vec![], |target, _| Ok(Value::Bool(!target.as_bool())));
+    class.add_native("!", vec![], |target, _| Ok(Value::Bool(!target.as_bool()?)));
     class.add_native("&&:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bool(arg) => Ok(Value::Bool(target.as_bool() && *arg)),
+        Value::Bool(arg) => Ok(Value::Bool(target.as_bool()? && *arg)),
         _ => expected("bool"),
     });
     class.add_native("||:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bool(arg) => Ok(Value::Bool(target.as_bool() || *arg)),
+        Value::Bool(arg) => Ok(Value::Bool(target.as_bool()? || *arg)),
         _ => expected("bool"),
     });
     class.add_native("^^:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bool(arg) => Ok(Value::Bool(target.as_bool() ^ *arg)),
+        Value::Bool(arg) => Ok(Value::Bool(target.as_bool()? ^ *arg)),
         _ => expected("bool"),
     });
     class.add_native("=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bool(arg) => Ok(Value::Bool(target.as_bool() == *arg)),
+        Value::Bool(arg) => Ok(Value::Bool(target.as_bool()? == *arg)),
         _ => Ok(Value::Bool(false)),
     });
     class.add(
@@ -77,12 +182,12 @@ fn build_bool_class() -> Rc<Class> {
             IR::Local(0),
             IR::SelfRef,
             IR::native(|ctx| {
-                let bool = ctx.pop().as_bool();
+                let bool = ctx.pop().as_bool()?;
                 let target = ctx.pop();
                 if bool {
-                    ctx.send("true", target, 0)?;
+                    ctx.send(Selector::TRUE, target, 0)?;
                 } else {
-                    ctx.send("false", target, 0)?;
+                    ctx.send(Selector::FALSE, target, 0)?;
                 }
                 Ok(())
             }),
@@ -95,66 +200,122 @@ fn build_int_class() -> Rc<Class> {
     let mut class = Class::new();
 
     class.add_native("+:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() + *arg)),
+        Value::Integer(arg) => Ok(match target.as_int()?.checked_add(*arg) {
+            Some(value) => Value::Integer(value),
+            None => big(BigInt::from(target.as_int()?).add(&BigInt::from(*arg))),
+        }),
+        Value::Float(arg) => Ok(Value::Float(target.as_int()? as f64 + *arg)),
+        Value::Bigint(arg) => Ok(big(BigInt::from(target.as_int()?).add(arg))),
         _ => expected("number"),
     });
     class.add_native("-:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() - *arg)),
+        Value::Integer(arg) => Ok(match target.as_int()?.checked_sub(*arg) {
+            Some(value) => Value::Integer(value),
+            None => big(BigInt::from(target.as_int()?).sub(&BigInt::from(*arg))),
+        }),
+        Value::Float(arg) => Ok(Value::Float(target.as_int()? as f64 - *arg)),
+        Value::Bigint(arg) => Ok(big(BigInt::from(target.as_int()?).sub(arg))),
         _ => expected("number"),
     });
     class.add_native("*:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() * *arg)),
+        Value::Integer(arg) => Ok(match target.as_int()?.checked_mul(*arg) {
+            Some(value) => Value::Integer(value),
+            None => big(BigInt::from(target.as_int()?).mul(&BigInt::from(*arg))),
+        }),
+        Value::Float(arg) => Ok(Value::Float(target.as_int()? as f64 * *arg)),
+        Value::Bigint(arg) => Ok(big(BigInt::from(target.as_int()?).mul(arg))),
         _ => expected("number"),
     });
+    class.add_native("/:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(target.as_int()? as f64 / as_number(&args[0])?))
+    });
     class.add_native("%:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int().rem_euclid(*arg))),
+        Value::Integer(0) => Err(RuntimeError::DivisionByZero),
+        Value::Integer(arg) => Ok(Value::Integer(int_mod(target.as_int()?, *arg))),
+        Value::Float(arg) => Ok(Value::Float(float_mod(target.as_int()? as f64, *arg))),
+        Value::Bigint(arg) => big_mod(&BigInt::from(target.as_int()?), arg),
         _ => expected("number"),
     });
     class.add_native("//:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() / (*arg))),
+        Value::Integer(0) => Err(RuntimeError::DivisionByZero),
+        Value::Integer(arg) => match int_div(target.as_int()?, *arg) {
+            Some(value) => Ok(Value::Integer(value)),
+            None => big_div(&BigInt::from(target.as_int()?), &BigInt::from(*arg)),
+        },
+        Value::Float(arg) => Ok(Value::Float(float_div(target.as_int()? as f64, *arg))),
+        Value::Bigint(arg) => big_div(&BigInt::from(target.as_int()?), arg),
         _ => expected("number"),
     });
-    class.add_native(">>:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() >> *arg)),
-        _ => expected("number"),
+    class.add_native(">>:", vec![Param::Value], |target, args| {
+        let bits = as_shift(&args[0])?;
+        Ok(Value::Integer(target.as_int()? >> bits.min(63)))
     });
-    class.add_native("<<:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() << *arg)),
-        _ => expected("number"),
+    class.add_native("<<:", vec![Param::Value], |target, args| {
+        let bits = as_shift(&args[0])?;
+        let value = target.as_int()?;
+        match value.checked_shl(bits as u32) {
+            Some(shifted) if bits < 64 && shifted >> bits == value => Ok(Value::Integer(shifted)),
+            _ => Ok(big(BigInt::from(value).shl(bits))),
+        }
     });
     class.add_native("&:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() & *arg)),
+        Value::Integer(arg) => Ok(Value::Integer(target.as_int()? & *arg)),
+        Value::Bigint(arg) => Ok(big(BigInt::from(target.as_int()?).and(arg))),
         _ => expected("number"),
     });
     class.add_native("|:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() | *arg)),
+        Value::Integer(arg) => Ok(Value::Integer(target.as_int()? | *arg)),
+        Value::Bigint(arg) => Ok(big(BigInt::from(target.as_int()?).or(arg))),
         _ => expected("number"),
     });
     class.add_native("^:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Integer(target.as_int() ^ *arg)),
+        Value::Integer(arg) => Ok(Value::Integer(target.as_int()? ^ *arg)),
+        Value::Bigint(arg) => Ok(big(BigInt::from(target.as_int()?).xor(arg))),
         _ => expected("number"),
     });
     class.add_native("-", vec![], |target, _| {
-        Ok(Value::Integer(-target.as_int()))
+        match target.as_int()?.checked_neg() {
+            Some(value) => Ok(Value::Integer(value)),
+            None => Ok(big(BigInt::from(target.as_int()?).neg())),
+        }
     });
     class.add_native("abs", vec![], |target, _| {
-        Ok(Value::Integer(target.as_int().abs()))
+        match target.as_int()?.checked_abs() {
+            Some(value) => Ok(Value::Integer(value)),
+            None => Ok(big(BigInt::from(target.as_int()?).abs())),
+        }
+    });
+    class.add_native("~", vec![], |target, _| {
+        Ok(Value::Integer(!target.as_int()?))
+    });
+    class.add_native("popcount", vec![], |target, _| match target.as_int()? {
+        value if value < 0 => panic("popcount of negative Int"),
+        value => Ok(Value::Integer(value.count_ones() as i64)),
     });
     class.add_native("hash", vec![], |target, _| {
         let mut state = DefaultHasher::new();
-        target.as_int().hash(&mut state);
+        target.as_int()?.hash(&mut state);
         Ok(Value::Integer(state.finish() as i64))
     });
     class.add_native("to String", vec![], |target, _| {
-        Ok(Value::String(Rc::new(target.as_int().to_string())))
+        Ok(Value::String(Rc::new(target.as_int()?.to_string())))
     });
-    // value comparison
+    class.add_native("to Int", vec![], |target, _| Ok(target));
+    class.add_native("to Float", vec![], |target, _| {
+        Ok(Value::Float(target.as_int()? as f64))
+    });
+    class.add_native("to BigInt", vec![], |target, _| {
+        Ok(big(BigInt::from(target.as_int()?)))
+    });
+    // value comparison, where ints & bigints are both integers
     class.add_native("=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() == *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? == *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) == **arg)),
         _ => Ok(Value::Bool(false)),
     });
     class.add_native("!=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() != *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? != *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) != **arg)),
         _ => Ok(Value::Bool(true)),
     });
     // numeric comparison
@@ -171,29 +332,175 @@ fn build_int_class() -> Rc<Class> {
         ],
     );
     class.add_native("<:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() < *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? < *arg)),
+        Value::Float(arg) => Ok(Value::Bool((target.as_int()? as f64) < *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) < **arg)),
         _ => expected("number"),
     });
     class.add_native("<=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() <= *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? <= *arg)),
+        Value::Float(arg) => Ok(Value::Bool((target.as_int()? as f64) <= *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) <= **arg)),
         _ => expected("number"),
     });
     class.add_native("==:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() == *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? == *arg)),
+        Value::Float(arg) => Ok(Value::Bool((target.as_int()? as f64) == *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) == **arg)),
         _ => expected("number"),
     });
     class.add_native("<>:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() != *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? != *arg)),
+        Value::Float(arg) => Ok(Value::Bool((target.as_int()? as f64) != *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) != **arg)),
         _ => expected("number"),
     });
     class.add_native(">=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() >= *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? >= *arg)),
+        Value::Float(arg) => Ok(Value::Bool((target.as_int()? as f64) >= *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) >= **arg)),
         _ => expected("number"),
     });
     class.add_native(">:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Integer(arg) => Ok(Value::Bool(target.as_int() > *arg)),
+        Value::Integer(arg) => Ok(Value::Bool(target.as_int()? > *arg)),
+        Value::Float(arg) => Ok(Value::Bool((target.as_int()? as f64) > *arg)),
+        Value::Bigint(arg) => Ok(Value::Bool(BigInt::from(target.as_int()?) > **arg)),
         _ => expected("number"),
     });
+    add_min_max(&mut class);
+
+    class.rc()
+}
+
+// a whole float as an int, instead of saturating when it is out of range
+fn float_to_int(value: f64) -> Runtime<Value> {
+    // i64::MIN is exact as a float, i64::MAX rounds up to 2^63
+    if value >= i64::MIN as f64 && value < i64::MAX as f64 {
+        Ok(Value::Integer(value as i64))
+    } else {
+        panic("Float is out of range for Int")
+    }
+}
+
+fn build_float_class() -> Rc<Class> {
+    let mut class = Class::new();
+
+    class.add_native("+:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(target.as_float()? + as_number(&args[0])?))
+    });
+    class.add_native("-:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(target.as_float()? - as_number(&args[0])?))
+    });
+    class.add_native("*:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(target.as_float()? * as_number(&args[0])?))
+    });
+    class.add_native("/:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(target.as_float()? / as_number(&args[0])?))
+    });
+    class.add_native("//:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(float_div(
+            target.as_float()?,
+            as_number(&args[0])?,
+        )))
+    });
+    class.add_native("%:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(float_mod(
+            target.as_float()?,
+            as_number(&args[0])?,
+        )))
+    });
+    class.add_native("-", vec![], |target, _| {
+        Ok(Value::Float(-target.as_float()?))
+    });
+    class.add_native("abs", vec![], |target, _| {
+        Ok(Value::Float(target.as_float()?.abs()))
+    });
+    class.add_native("hash", vec![], |target, _| {
+        let mut state = DefaultHasher::new();
+        // equal floats hash the same, so -0.0 is hashed as 0.0
+        let value = target.as_float()?;
+        let value = if value == 0.0 {
+            0.0
+        } else if value.is_nan() {
+            f64::NAN
+        } else {
+            value
+        };
+        value.to_bits().hash(&mut state);
+        Ok(Value::Integer(state.finish() as i64))
+    });
+    class.add_native("to String", vec![], |target, _| {
+        Ok(Value::String(Rc::new(format!("{:?}", target.as_float()?))))
+    });
+    // rounding, to the nearest int
+    class.add_native("round", vec![], |target, _| {
+        float_to_int(target.as_float()?.round())
+    });
+    class.add_native("floor", vec![], |target, _| {
+        float_to_int(target.as_float()?.floor())
+    });
+    class.add_native("ceil", vec![], |target, _| {
+        float_to_int(target.as_float()?.ceil())
+    });
+    class.add_native("truncate", vec![], |target, _| {
+        float_to_int(target.as_float()?.trunc())
+    });
+    class.add_native("to Int", vec![], |target, _| {
+        float_to_int(target.as_float()?.trunc())
+    });
+    class.add_native("to Float", vec![], |target, _| Ok(target));
+    // value comparison
+    class.add_native("=:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Bool(target.as_float()? == *arg)),
+        _ => Ok(Value::Bool(false)),
+    });
+    class.add_native("!=:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Bool(target.as_float()? != *arg)),
+        _ => Ok(Value::Bool(true)),
+    });
+    // numeric comparison, with ints compared as floats
+    class.add(
+        "order:",
+        vec![Param::Value],
+        vec![
+            IR::Local(0),
+            IR::SelfRef,
+            IR::SendNative(
+                |target, args| match target.as_float()?.partial_cmp(&as_number(&args[0])?) {
+                    Some(ordering) => Ok(Value::Integer(ordering as i64)),
+                    None => panic("cannot order NaN"),
+                },
+                1,
+            ),
+            IR::Module("core/ord".to_string()),
+            IR::send("Ord", 0),
+            IR::send("from int:", 1),
+        ],
+    );
+    class.add_native("<:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(target.as_float()? < as_number(&args[0])?))
+    });
+    class.add_native("<=:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(target.as_float()? <= as_number(&args[0])?))
+    });
+    class.add_native("==:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(target.as_float()? == as_number(&args[0])?))
+    });
+    class.add_native("<>:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(target.as_float()? != as_number(&args[0])?))
+    });
+    class.add_native(">=:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(target.as_float()? >= as_number(&args[0])?))
+    });
+    class.add_native(">:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(target.as_float()? > as_number(&args[0])?))
+    });
+    add_min_max(&mut class);
+
+    class.rc()
+}
+
+fn add_min_max(class: &mut Class) {
     class.add(
         "min:",
         vec![Param::Value],
@@ -229,14 +536,12 @@ fn build_int_class() -> Rc<Class> {
             IR::send("max:", 1),
         ],
     );
-
-    class.rc()
 }
 
 fn build_string_class() -> Rc<Class> {
     let mut class = Class::new();
     class.add_native("length", vec![], |target, _| {
-        Ok(Value::Integer(target.as_string().len() as i64))
+        Ok(Value::Integer(target.as_string()?.len() as i64))
     });
 
     class.add(
@@ -250,7 +555,7 @@ fn build_string_class() -> Rc<Class> {
                 |target, args| match &args[0] {
                     Value::String(str) => Ok(Value::String(Rc::new(format!(
                         "{}{}",
-                        target.as_string(),
+                        target.as_string()?,
                         str
                     )))),
                     _ => expected("string"),
@@ -261,17 +566,17 @@ fn build_string_class() -> Rc<Class> {
     );
 
     class.add_native("=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::String(arg) => Ok(Value::Bool(target.as_string() == *arg)),
+        Value::String(arg) => Ok(Value::Bool(target.as_string()? == *arg)),
         _ => Ok(Value::Bool(false)),
     });
     class.add_native("!=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::String(arg) => Ok(Value::Bool(target.as_string() != *arg)),
+        Value::String(arg) => Ok(Value::Bool(target.as_string()? != *arg)),
         _ => Ok(Value::Bool(true)),
     });
 
     fn code_at_unchecked(target: Value, mut args: Vec<Value>) -> Runtime<Value> {
-        let idx = args.pop().unwrap().as_int();
-        let ch = target.as_string().chars().nth(idx as usize).unwrap();
+        let idx = args.pop().unwrap().as_int()?;
+        let ch = char_at(&target.as_string()?, idx)?;
         Ok(Value::Integer(ch as i64))
     }
 
@@ -298,8 +603,8 @@ fn build_string_class() -> Rc<Class> {
             IR::SelfRef,
             IR::SendNative(
                 |target, mut args| {
-                    let idx = args.pop().unwrap().as_int();
-                    let ch = target.as_string().chars().nth(idx as usize).unwrap();
+                    let idx = args.pop().unwrap().as_int()?;
+                    let ch = char_at(&target.as_string()?, idx)?;
                     Ok(Value::String(Rc::new(ch.to_string())))
                 },
                 1,
@@ -314,7 +619,7 @@ fn build_string_class() -> Rc<Class> {
             IR::SendNative(
                 |target, _| {
                     let chars = target
-                        .as_string()
+                        .as_string()?
                         .chars()
                         .map(|ch| Value::String(Rc::new(ch.to_string())))
                         .collect::<Vec<_>>();
@@ -333,7 +638,7 @@ fn build_string_class() -> Rc<Class> {
 
     class.add_native("hash", vec![], |target, _| {
         let mut state = DefaultHasher::new();
-        target.as_string().hash(&mut state);
+        target.as_string()?.hash(&mut state);
         Ok(Value::Integer(state.finish() as i64))
     });
 
@@ -343,10 +648,10 @@ fn build_string_class() -> Rc<Class> {
 fn build_array_class() -> Rc<Class> {
     let mut class = Class::new();
     class.add_native("length", vec![], |target, _| {
-        Ok(Value::Integer(target.as_array().borrow().len() as i64))
+        Ok(Value::Integer(target.as_array()?.borrow().len() as i64))
     });
     class.add_native("push:", vec![Param::Value], |target, mut args| {
-        target.as_array().borrow_mut().push(args.pop().unwrap());
+        target.as_array()?.borrow_mut().push(args.pop().unwrap());
         Ok(Value::Unit)
     });
     // TODO: distinguish between at: with / without wrapping
@@ -358,9 +663,11 @@ fn build_array_class() -> Rc<Class> {
             IR::SelfRef,
             IR::SendNative(
                 |target, mut args| {
-                    let idx = args.pop().unwrap().as_int();
-                    let value = target.as_array().borrow()[idx as usize].clone();
-                    Ok(value)
+                    let idx = args.pop().unwrap().as_int()?;
+                    let array = target.as_array()?;
+                    let array = array.borrow();
+                    let idx = array_index(idx, array.len())?;
+                    Ok(array[idx].clone())
                 },
                 1,
             ),
@@ -376,8 +683,11 @@ fn build_array_class() -> Rc<Class> {
             IR::SendNative(
                 |target, mut args| {
                     let value = args.pop().unwrap();
-                    let idx = args.pop().unwrap().as_int();
-                    target.as_array().borrow_mut()[idx as usize] = value;
+                    let idx = args.pop().unwrap().as_int()?;
+                    let array = target.as_array()?;
+                    let mut array = array.borrow_mut();
+                    let idx = array_index(idx, array.len())?;
+                    array[idx] = value;
                     Ok(Value::Unit)
                 },
                 2,
@@ -394,9 +704,19 @@ fn build_array_class() -> Rc<Class> {
             IR::SelfRef,
             IR::SendNative(
                 |target, mut args| {
-                    let to = args.pop().unwrap().as_int() as usize;
-                    let from = args.pop().unwrap().as_int() as usize;
-                    let slice = target.as_array().borrow()[from..to].to_vec();
+                    let to = args.pop().unwrap().as_int()?;
+                    let from = args.pop().unwrap().as_int()?;
+                    let array = target.as_array()?;
+                    let array = array.borrow();
+                    let to = match usize::try_from(to) {
+                        Ok(to) if to <= array.len() => to,
+                        _ => return Err(RuntimeError::IndexOutOfRange(to, array.len())),
+                    };
+                    let from = match usize::try_from(from) {
+                        Ok(from) if from <= to => from,
+                        _ => return Err(RuntimeError::IndexOutOfRange(from, array.len())),
+                    };
+                    let slice = array[from..to].to_vec();
                     Ok(Value::mut_array(slice))
                 },
                 2,
@@ -415,7 +735,7 @@ fn build_array_class() -> Rc<Class> {
         ],
     );
     class.add_native("reverse", vec![], |target, _| {
-        target.as_array().borrow_mut().reverse();
+        target.as_array()?.borrow_mut().reverse();
         Ok(Value::Unit)
     });
     class.rc()
@@ -423,40 +743,164 @@ fn build_array_class() -> Rc<Class> {
 
 fn build_big_int_class() -> Rc<Class> {
     let mut class = Class::new();
-    class.add_native("<<:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bigint(target.as_bigint() << *val)),
-        _ => expected("bigint"),
+
+    class.add_native("+:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Float(target.as_bigint()?.to_f64() + *arg)),
+        arg => Ok(big(target.as_bigint()?.add(&as_bigint(arg)?))),
     });
-    class.add_native(">>:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bigint(target.as_bigint() >> *val)),
-        _ => expected("bigint"),
+    class.add_native("-:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Float(target.as_bigint()?.to_f64() - *arg)),
+        arg => Ok(big(target.as_bigint()?.sub(&as_bigint(arg)?))),
     });
-    class.add_native("|:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bigint(target.as_bigint() | *val)),
-        _ => expected("bigint"),
+    class.add_native("*:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Float(target.as_bigint()?.to_f64() * *arg)),
+        arg => Ok(big(target.as_bigint()?.mul(&as_bigint(arg)?))),
     });
-    class.add_native("&:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bigint(target.as_bigint() & *val)),
-        _ => expected("bigint"),
+    class.add_native("/:", vec![Param::Value], |target, args| {
+        Ok(Value::Float(
+            target.as_bigint()?.to_f64() / as_number(&args[0])?,
+        ))
     });
-    class.add_native("^:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bigint(target.as_bigint() ^ *val)),
-        _ => expected("bigint"),
+    class.add_native("%:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Float(float_mod(target.as_bigint()?.to_f64(), *arg))),
+        arg => big_mod(&*target.as_bigint()?, &as_bigint(arg)?),
     });
-    class.add_native("~", vec![], |target, _| {
-        Ok(Value::Bigint(!target.as_bigint()))
+    class.add_native("//:", vec![Param::Value], |target, args| match &args[0] {
+        Value::Float(arg) => Ok(Value::Float(float_div(target.as_bigint()?.to_f64(), *arg))),
+        arg => big_div(&*target.as_bigint()?, &as_bigint(arg)?),
+    });
+    class.add_native("<<:", vec![Param::Value], |target, args| {
+        Ok(big(target.as_bigint()?.shl(as_shift(&args[0])?)))
+    });
+    class.add_native(">>:", vec![Param::Value], |target, args| {
+        Ok(big(target.as_bigint()?.shr(as_shift(&args[0])?)))
+    });
+    class.add_native("|:", vec![Param::Value], |target, args| {
+        Ok(big(target.as_bigint()?.or(&as_bigint(&args[0])?)))
+    });
+    class.add_native("&:", vec![Param::Value], |target, args| {
+        Ok(big(target.as_bigint()?.and(&as_bigint(&args[0])?)))
+    });
+    class.add_native("^:", vec![Param::Value], |target, args| {
+        Ok(big(target.as_bigint()?.xor(&as_bigint(&args[0])?)))
+    });
+    class.add_native("~", vec![], |target, _| Ok(big(target.as_bigint()?.not())));
+    class.add_native("-", vec![], |target, _| Ok(big(target.as_bigint()?.neg())));
+    class.add_native("abs", vec![], |target, _| {
+        Ok(big(target.as_bigint()?.abs()))
+    });
+    class.add_native("popcount", vec![], |target, _| {
+        match target.as_bigint()?.count_ones() {
+            Some(count) => Ok(Value::Integer(count as i64)),
+            None => panic("popcount of negative BigInt"),
+        }
+    });
+    // hashes equal to the int with the same value
+    class.add_native("hash", vec![], |target, _| {
+        let mut state = DefaultHasher::new();
+        let value = target.as_bigint()?;
+        match value.to_i64() {
+            Some(value) => value.hash(&mut state),
+            None => value.hash(&mut state),
+        }
+        Ok(Value::Integer(state.finish() as i64))
+    });
+    class.add_native("to String", vec![], |target, _| {
+        Ok(Value::String(Rc::new(target.as_bigint()?.to_string())))
+    });
+    class.add_native("to Int", vec![], |target, _| {
+        match target.as_bigint()?.to_i64() {
+            Some(value) => Ok(Value::Integer(value)),
+            None => panic("BigInt is too large for Int"),
+        }
+    });
+    class.add_native("to Float", vec![], |target, _| {
+        Ok(Value::Float(target.as_bigint()?.to_f64()))
     });
+    class.add_native("to BigInt", vec![], |target, _| Ok(target));
+    // value comparison, where ints & bigints are both integers
     class.add_native("=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bool(target.as_bigint() == *val)),
+        Value::Integer(_) | Value::Bigint(_) => {
+            Ok(Value::Bool(*target.as_bigint()? == as_bigint(&args[0])?))
+        }
         _ => Ok(Value::Bool(false)),
     });
     class.add_native("!=:", vec![Param::Value], |target, args| match &args[0] {
-        Value::Bigint(val) => Ok(Value::Bool(target.as_bigint() != *val)),
+        Value::Integer(_) | Value::Bigint(_) => {
+            Ok(Value::Bool(*target.as_bigint()? != as_bigint(&args[0])?))
+        }
         _ => Ok(Value::Bool(true)),
     });
-    class.add_native("popcount", vec![], |target, _| {
-        Ok(Value::Integer(target.as_bigint().count_ones() as i64))
+    // numeric comparison
+    class.add(
+        "order:",
+        vec![Param::Value],
+        vec![
+            IR::Local(0),
+            IR::SelfRef,
+            IR::SendNative(
+                |target, args| {
+                    let ordering = target.as_bigint()?.as_ref().cmp(&as_bigint(&args[0])?);
+                    Ok(Value::Integer(ordering as i64))
+                },
+                1,
+            ),
+            IR::Module("core/ord".to_string()),
+            IR::send("Ord", 0),
+            IR::send("from int:", 1),
+        ],
+    );
+    class.add_native("<:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(*target.as_bigint()? < as_bigint(&args[0])?))
+    });
+    class.add_native("<=:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(*target.as_bigint()? <= as_bigint(&args[0])?))
+    });
+    class.add_native("==:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(*target.as_bigint()? == as_bigint(&args[0])?))
+    });
+    class.add_native("<>:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(*target.as_bigint()? != as_bigint(&args[0])?))
+    });
+    class.add_native(">=:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(*target.as_bigint()? >= as_bigint(&args[0])?))
+    });
+    class.add_native(">:", vec![Param::Value], |target, args| {
+        Ok(Value::Bool(*target.as_bigint()? > as_bigint(&args[0])?))
     });
+    add_min_max(&mut class);
+
+    class.rc()
+}
+
+// (expected received -- ()), failing at the position the current handler was called from
+fn assert_equal(ctx: &mut Interpreter) -> Runtime<()> {
+    let received = ctx.pop();
+    let expected = ctx.pop();
+    if expected == received {
+        ctx.push(Value::Unit);
+        Ok(())
+    } else {
+        Err(RuntimeError::AssertionFailed(
+            expected,
+            received,
+            ctx.call_site(),
+        ))
+    }
+}
+
+fn build_assert_class() -> Rc<Class> {
+    let mut class = Class::new();
+    class.add(
+        "expected:received:",
+        vec![Param::Value, Param::Value],
+        vec![IR::Local(0), IR::Local(1), IR::native(assert_equal)],
+    );
+    class.add(
+        ":",
+        vec![Param::Value],
+        vec![IR::bool(true), IR::Local(0), IR::native(assert_equal)],
+    );
     class.rc()
 }
 
@@ -465,23 +909,14 @@ fn build_native_module() -> Rc<Class> {
     class.add(
         "expected:received:",
         vec![Param::Value, Param::Value],
-        vec![
-            IR::Local(0),
-            IR::Local(1),
-            IR::SendNative(
-                |received, args| {
-                    if args[0] == received {
-                        Ok(Value::Unit)
-                    } else {
-                        Err(RuntimeError::Panic(format!(
-                            "expected: {:?} received: {:?}",
-                            &args[0], received
-                        )))
-                    }
-                },
-                1,
-            ),
-        ],
+        vec![IR::Local(0), IR::Local(1), IR::native(assert_equal)],
+    );
+    class.add(
+        "Assert",
+        vec![],
+        vec![IR::Constant(Value::Object(
+            Object::new(build_assert_class(), vec![]).rc(),
+        ))],
     );
     class.add(
         "log:",
@@ -491,7 +926,7 @@ fn build_native_module() -> Rc<Class> {
             IR::send("to String", 0),
             IR::SendNative(
                 |target, _| {
-                    println!("{}", target.as_string());
+                    println!("{}", target.as_string()?);
                     Ok(Value::Unit)
                 },
                 0,
@@ -499,21 +934,60 @@ fn build_native_module() -> Rc<Class> {
         ],
     );
     class.add_native("panic:", vec![Param::Value], |_, args| {
-        Err(RuntimeError::Panic(format!("{:?}", args[0])))
+        Err(RuntimeError::Panic(args[0].clone()))
     });
+    // calls f, or handler with the payload if f panics
+    class.add(
+        "catch:try:",
+        vec![Param::Do, Param::Do],
+        vec![
+            IR::Local(0),
+            IR::Local(1),
+            IR::native(|ctx| {
+                let f = ctx.pop();
+                let handler = ctx.pop();
+                ctx.try_send(f, handler)
+            }),
+            IR::native(|ctx| {
+                ctx.end_unwinder();
+                Ok(())
+            }),
+        ],
+    );
+    // calls f, then cleanup, even if f returns early or panics
+    class.add(
+        "do:ensure:",
+        vec![Param::Do, Param::Do],
+        vec![
+            IR::Local(0),
+            IR::native(|ctx| {
+                let f = ctx.pop();
+                ctx.ensure_send(f)
+            }),
+            IR::native(|ctx| {
+                ctx.end_unwinder();
+                Ok(())
+            }),
+            IR::Local(1),
+            IR::send("", 0),
+            IR::Drop,
+            IR::native(|ctx| ctx.resume_unwinding()),
+        ],
+    );
     class.add(
         "loop:",
         vec![Param::Do],
-        vec![IR::Local(0), IR::send("", 0), IR::Loop],
+        vec![IR::Local(0), IR::send("", 0), IR::Drop, IR::Loop],
     );
     class.add(
         "string from char code:",
         vec![Param::Value],
         vec![IR::SendNative(
             |code, _| match code {
-                Value::Integer(int) => Ok(Value::String(Rc::new(
-                    char::from_u32(int as u32).unwrap().to_string(),
-                ))),
+                Value::Integer(int) => match u32::try_from(int).ok().and_then(char::from_u32) {
+                    Some(ch) => Ok(Value::String(Rc::new(ch.to_string()))),
+                    None => Err(RuntimeError::InvalidCharCode(int)),
+                },
                 _ => expected("integer"),
             },
             0,
@@ -534,14 +1008,21 @@ fn build_native_module() -> Rc<Class> {
         |_, args| match &args[0] {
             Value::String(path) => match std::fs::read_to_string(path.deref()) {
                 Ok(str) => Ok(Value::String(Rc::new(str))),
-                Err(_) => Err(RuntimeError::Panic("failed to read file".to_string())),
+                Err(_) => panic("failed to read file"),
             },
             _ => expected("string"),
         },
     );
-    class.add_native("BigInt:", vec![Param::Value], |_, args| match &args[0] {
-        Value::Integer(int) => Ok(Value::Bigint(*int as u128)),
-        _ => expected("integer"),
+    class.add_native("BigInt:", vec![Param::Value], |_, args| {
+        Ok(big(as_bigint(&args[0])?))
+    });
+    class.add_native("args", vec![], |_, _| {
+        let args = PROCESS_ARGS.with(|args| args.borrow().clone());
+        Ok(Value::mut_array(
+            args.into_iter()
+                .map(|arg| Value::String(Rc::new(arg)))
+                .collect(),
+        ))
     });
     class.rc()
 }
@@ -550,10 +1031,16 @@ thread_local! {
     static UNIT_CLASS: Rc<Class> = Class::new().rc();
     static BOOL_CLASS: Rc<Class> = build_bool_class();
     static INT_CLASS: Rc<Class> = build_int_class();
+    static FLOAT_CLASS: Rc<Class> = build_float_class();
     static STRING_CLASS: Rc<Class> = build_string_class();
     static ARRAY_CLASS: Rc<Class> = build_array_class();
     static BIG_INT_CLASS: Rc<Class>= build_big_int_class();
     static NATIVE_MODULE: Rc<Class> = build_native_module();
+    static PROCESS_ARGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn set_process_args(args: Vec<String>) {
+    PROCESS_ARGS.with(|cell| *cell.borrow_mut() = args);
 }
 
 pub fn unit_class() -> Rc<Class> {
@@ -565,6 +1052,9 @@ pub fn bool_class() -> Rc<Class> {
 pub fn int_class() -> Rc<Class> {
     INT_CLASS.with(|c| c.clone())
 }
+pub fn float_class() -> Rc<Class> {
+    FLOAT_CLASS.with(|c| c.clone())
+}
 pub fn string_class() -> Rc<Class> {
     STRING_CLASS.with(|c| c.clone())
 }
@@ -0,0 +1,492 @@
+use std::rc::Rc;
+
+use crate::{
+    compiler::{drop_locals, IRBuilder},
+    grammar::{Inlined, Position},
+    ir::{Address, Arity, Handler, InlineCache, NativeFn, Param, Selector, Value, IR},
+    native::int_class,
+};
+
+// an instruction & the source position it reports errors at
+pub type Instruction = (IR, Option<Position>);
+
+// rewrites the instructions of a body, keeping each instruction's position
+pub type Pass = fn(Vec<Instruction>) -> Vec<Instruction>;
+
+pub const ALL_PASSES: &[Pass] = &[
+    remove_unreachable,
+    fold_constants,
+    drop_unused,
+    primitive_sends,
+];
+
+// runs the passes in order over a compiled body, before its labels are linked. Jumps can only
+// land on labels, so passes that only look at neighbouring instructions don't need to know
+// about control flow.
+pub fn optimize(passes: &[Pass], builder: IRBuilder) -> IRBuilder {
+    if passes.is_empty() {
+        return builder;
+    }
+    let (ir, positions) = builder.build_with_positions();
+    let mut body = ir
+        .into_iter()
+        .enumerate()
+        .map(|(index, ir)| (ir, positions.get(index).cloned()))
+        .collect::<Vec<_>>();
+    for pass in passes {
+        body = pass(body);
+    }
+    let mut builder = IRBuilder::new();
+    for (ir, position) in body {
+        builder.push_at(ir, position);
+    }
+    builder
+}
+
+// nothing after a return or jump is evaluated, until the next label
+pub fn remove_unreachable(body: Vec<Instruction>) -> Vec<Instruction> {
+    let mut reachable = true;
+    let mut out = vec![];
+    for (ir, position) in body {
+        match ir {
+            IR::Label(_) => reachable = true,
+            _ if !reachable => continue,
+            IR::Return | IR::Jump(_) => reachable = false,
+            _ => {}
+        }
+        out.push((ir, position));
+    }
+    out
+}
+
+// native sends to int & bool constants with int & bool constant args are evaluated at
+// compile time. Sends that fail are left for the runtime to report.
+pub fn fold_constants(body: Vec<Instruction>) -> Vec<Instruction> {
+    let mut out: Vec<Instruction> = vec![];
+    for (ir, position) in body {
+        match fold(&out, &ir) {
+            Some((arity, value)) => {
+                out.truncate(out.len() - arity - 1);
+                out.push((IR::Constant(value), None));
+            }
+            None => out.push((ir, position)),
+        }
+    }
+    out
+}
+
+fn fold(out: &[Instruction], ir: &IR) -> Option<(Arity, Value)> {
+    let arity = match ir {
+        IR::Send(_, arity, _) | IR::SendDirect(_, arity) | IR::SendNative(_, arity) => *arity,
+        _ => return None,
+    };
+    let mut args = vec![];
+    for (ir, _) in &out[out.len().checked_sub(arity + 1)?..] {
+        match ir {
+            IR::Constant(value @ (Value::Integer(_) | Value::Bool(_))) => args.push(value.clone()),
+            _ => return None,
+        }
+    }
+    let target = args.pop()?;
+    let handler = match ir {
+        IR::SendNative(f, _) => return Some((arity, f(target, args).ok()?)),
+        IR::SendDirect(handler, _) => handler.clone(),
+        IR::Send(selector, _, _) => target.class().ok()?.get(*selector).ok()?,
+        _ => return None,
+    };
+    match handler.body[..] {
+        [IR::SelfRef, IR::SendNative(f, _)] => Some((arity, f(target, args).ok()?)),
+        _ => None,
+    }
+}
+
+// values that are pushed & immediately dropped are never pushed
+pub fn drop_unused(body: Vec<Instruction>) -> Vec<Instruction> {
+    let mut out: Vec<Instruction> = vec![];
+    for (ir, position) in body {
+        match (&ir, out.last()) {
+            (IR::Drop, Some((IR::Constant(_) | IR::Local(_), _))) => {
+                out.pop();
+            }
+            _ => out.push((ir, position)),
+        }
+    }
+    out
+}
+
+// operator sends that may be to ints use instructions with an int fast path, including
+// inlined sends to int constants. Direct sends to other constants already skip the handler
+// lookup.
+pub fn primitive_sends(body: Vec<Instruction>) -> Vec<Instruction> {
+    body.into_iter()
+        .map(|(ir, position)| match ir {
+            // the native send of an inlined int handler becomes a send to the handler again
+            IR::SendNative(..) => match primitive_send(ir) {
+                ir @ IR::SendNative(..) => (ir, position),
+                ir => (ir, position.map(outside_inlined)),
+            },
+            ir => (primitive_send(ir), position),
+        })
+        .collect()
+}
+
+// the position without its innermost inlined handler
+fn outside_inlined(position: Position) -> Position {
+    let inlined = position.inlined.as_ref().and_then(|inlined| {
+        let inner = inlined.position.clone()?;
+        inner.inlined.as_ref()?;
+        Some(Rc::new(Inlined {
+            selector: inlined.selector,
+            position: Some(outside_inlined(inner)),
+        }))
+    });
+    Position {
+        inlined,
+        ..position
+    }
+}
+
+fn primitive_send(ir: IR) -> IR {
+    let selector = match &ir {
+        IR::Send(selector, 1, _) => *selector,
+        IR::SendDirect(handler, 1)
+            if int_class()
+                .get(handler.selector)
+                .is_ok_and(|int_handler| Rc::ptr_eq(&int_handler, handler)) =>
+        {
+            handler.selector
+        }
+        IR::SendNative(f, 1) => {
+            match [Selector::ADD, Selector::SUB, Selector::LT, Selector::EQ]
+                .into_iter()
+                .find(|selector| is_int_native(*f, *selector))
+            {
+                Some(selector) => selector,
+                None => return ir,
+            }
+        }
+        _ => return ir,
+    };
+    match selector {
+        Selector::ADD => IR::Add(InlineCache::default()),
+        Selector::SUB => IR::Sub(InlineCache::default()),
+        Selector::LT => IR::Lt(InlineCache::default()),
+        Selector::EQ => IR::Eq(InlineCache::default()),
+        _ => ir,
+    }
+}
+
+// native fns are compared by address, which is stable within a build
+#[allow(unpredictable_function_pointer_comparisons)]
+fn is_int_native(f: NativeFn, selector: Selector) -> bool {
+    int_class().get(selector).is_ok_and(|handler| {
+        matches!(handler.body[..], [IR::SelfRef, IR::SendNative(native, 1)] if native == f)
+    })
+}
+
+// the body of a handler, to be spliced in place of a send of args. Self & ivals are replaced
+// by the target's values, or kept for a send to self (`None`), and params by the args, which
+// must each be a single load so that they can be evaluated where they're used. The handler's
+// own locals are renumbered to sit above `base`, the caller's stack height where the body
+// starts, & the result is moved down over them at the end. Instructions are rewritten one
+// for one, so the handler's positions still apply to them. Handlers with frame state or
+// control flow, or more than `budget` instructions, are sent as usual.
+pub fn inline_handler(
+    target: Option<&Value>,
+    handler: &Handler,
+    args: &[IR],
+    base: Address,
+    budget: usize,
+) -> Option<Vec<IR>> {
+    if handler.params.iter().any(|param| *param != Param::Value)
+        || !args.iter().all(|arg| {
+            matches!(
+                arg,
+                IR::Constant(_) | IR::Local(_) | IR::IVal(_) | IR::SelfRef
+            )
+        })
+    {
+        return None;
+    }
+    // native handlers send their params, which are the locals under self
+    let body = match handler.body[..] {
+        [IR::SelfRef, IR::SendNative(f, arity)] => (0..arity)
+            .map(IR::Local)
+            .chain([IR::SelfRef, IR::SendNative(f, arity)])
+            .collect(),
+        _ => handler.body.to_vec(),
+    };
+    if body.len() > budget {
+        return None;
+    }
+    let params = handler.params.len();
+    let mut out = vec![];
+    // values pushed by the body, which must end with its locals & its result
+    let mut height: isize = 0;
+    for ir in body {
+        let (ir, pushed) = match (ir, target) {
+            (IR::SelfRef, Some(target)) => (IR::Constant(target.clone()), 1),
+            (IR::IVal(index), Some(target)) => (IR::Constant(target.ival(index).ok()?), 1),
+            (IR::NewSelf(arity), Some(target)) => {
+                (IR::Object(target.class().ok()?, arity), 1 - arity as isize)
+            }
+            (ir @ (IR::SelfRef | IR::IVal(_)), None) => (ir, 1),
+            (ir @ IR::NewSelf(arity), None) => (ir, 1 - arity as isize),
+            (IR::Local(index), _) if index < params => (args[index].clone(), 1),
+            // locals are bound in order at the bottom of the body's stack
+            (IR::Local(index), _) if ((index - params) as isize) < height => {
+                (IR::Local(base + index - params), 1)
+            }
+            (ir @ IR::Constant(_), _) => (ir, 1),
+            (ir @ IR::Object(_, arity), _) => (ir, 1 - arity as isize),
+            (
+                ir @ (IR::Send(_, arity, _) | IR::SendDirect(_, arity) | IR::SendNative(_, arity)),
+                _,
+            ) => (ir, -(arity as isize)),
+            (ir @ (IR::Add(_) | IR::Sub(_) | IR::Lt(_) | IR::Eq(_) | IR::Drop), _) => (ir, -1),
+            _ => return None,
+        };
+        height += pushed;
+        out.push(ir);
+    }
+    if height < 1 {
+        return None;
+    }
+    out.extend(drop_locals(base, height as usize - 1));
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn position(line: usize) -> Option<Position> {
+        Some(Position {
+            file: Rc::from("<test>"),
+            line,
+            column: 1,
+            inlined: None,
+        })
+    }
+
+    fn body(ir: Vec<IR>) -> Vec<Instruction> {
+        ir.into_iter().map(|ir| (ir, None)).collect()
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(
+            remove_unreachable(body(vec![IR::int(1), IR::Return, IR::int(2), IR::Return])),
+            body(vec![IR::int(1), IR::Return])
+        );
+        // code after a label can be jumped to
+        assert_eq!(
+            remove_unreachable(body(vec![
+                IR::bool(true),
+                IR::JumpIfFalse(1),
+                IR::int(1),
+                IR::Return,
+                IR::unit(),
+                IR::Jump(2),
+                IR::Label(1),
+                IR::int(2),
+                IR::Label(2),
+            ])),
+            body(vec![
+                IR::bool(true),
+                IR::JumpIfFalse(1),
+                IR::int(1),
+                IR::Return,
+                IR::Label(1),
+                IR::int(2),
+                IR::Label(2),
+            ])
+        );
+    }
+
+    #[test]
+    fn folds_constants() {
+        // (1 + 2) * 3 = 9
+        assert_eq!(
+            fold_constants(vec![
+                (IR::int(3), None),
+                (IR::int(2), None),
+                (IR::int(1), None),
+                (IR::send("+:", 1), position(1)),
+                (IR::send("*:", 1), position(1)),
+            ]),
+            body(vec![IR::int(9)])
+        );
+        assert_eq!(
+            fold_constants(body(vec![IR::bool(true), IR::send("!", 0)])),
+            body(vec![IR::bool(false)])
+        );
+        // errors are raised at runtime
+        let division = vec![
+            (IR::int(0), None),
+            (IR::int(1), None),
+            (IR::send("%:", 1), position(1)),
+        ];
+        assert_eq!(fold_constants(division.clone()), division);
+        // only native handlers are evaluated
+        let handler = body(vec![IR::int(1), IR::int(2), IR::send("!=:", 1)]);
+        assert_eq!(fold_constants(handler), body(vec![IR::bool(true)]));
+        let handler = body(vec![IR::bool(true), IR::bool(false), IR::send("!=:", 1)]);
+        assert_eq!(fold_constants(handler.clone()), handler);
+    }
+
+    #[test]
+    fn drops_unused() {
+        assert_eq!(
+            drop_unused(body(vec![
+                IR::Local(0),
+                IR::int(1),
+                IR::Drop,
+                IR::Drop,
+                IR::SelfRef,
+                IR::Drop,
+                IR::unit(),
+            ])),
+            body(vec![IR::SelfRef, IR::Drop, IR::unit()])
+        );
+    }
+
+    #[test]
+    fn remaps_positions() {
+        let mut builder = IRBuilder::new();
+        builder.push(IR::int(2));
+        builder.push(IR::int(1));
+        builder.push_at(IR::send("+:", 1), position(1));
+        builder.push(IR::Drop);
+        builder.push(IR::SelfRef);
+        builder.push_at(IR::send("foo", 0), position(2));
+        let (ir, positions) = optimize(ALL_PASSES, builder).build_with_positions();
+        assert_eq!(ir, vec![IR::SelfRef, IR::send("foo", 0)]);
+        assert_eq!(positions.get(0), None);
+        assert_eq!(positions.get(1), position(2).as_ref());
+    }
+
+    #[test]
+    fn uses_primitive_sends() {
+        let direct = |target: Value, selector: &str| {
+            let handler = target
+                .class()
+                .unwrap()
+                .get(Selector::new(selector))
+                .unwrap();
+            IR::SendDirect(handler, 1)
+        };
+        assert_eq!(
+            primitive_sends(body(vec![
+                IR::send("+:", 1),
+                IR::send("-:", 1),
+                direct(Value::Integer(1), "<:"),
+                direct(Value::Bool(true), "=:"),
+                IR::send("*:", 1),
+            ])),
+            body(vec![
+                IR::Add(InlineCache::default()),
+                IR::Sub(InlineCache::default()),
+                IR::Lt(InlineCache::default()),
+                direct(Value::Bool(true), "=:"),
+                IR::send("*:", 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn inlines_handlers() {
+        let get = |target: &Value, selector: &str| {
+            target
+                .class()
+                .unwrap()
+                .get(Selector::new(selector))
+                .unwrap()
+        };
+        let mut class = crate::ir::Class::new();
+        class.add("x", vec![], vec![IR::IVal(0)]);
+        class.add("x:", vec![Param::Value], vec![IR::Local(0), IR::NewSelf(1)]);
+        class.add(
+            "double:",
+            vec![Param::Value],
+            vec![IR::Local(0), IR::Local(0), IR::send("+:", 1)],
+        );
+        class.add("let:", vec![Param::Value], vec![IR::Local(0), IR::Local(1)]);
+        class.add(
+            "lets:",
+            vec![Param::Value],
+            vec![
+                IR::Local(0),
+                IR::int(1),
+                IR::Local(2),
+                IR::Local(1),
+                IR::send("+:", 1),
+            ],
+        );
+        class.add("do:", vec![Param::Do], vec![IR::Local(0)]);
+        let class = class.rc();
+        let point =
+            Value::Object(crate::ir::Object::new(class.clone(), vec![Value::Integer(1)]).rc());
+
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "x"), &[], 4, 8),
+            Some(vec![IR::int(1)])
+        );
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "x:"), &[IR::Local(3)], 4, 8),
+            Some(vec![IR::Local(3), IR::Object(class, 1)])
+        );
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "double:"), &[IR::SelfRef], 4, 8),
+            Some(vec![IR::SelfRef, IR::SelfRef, IR::send("+:", 1)])
+        );
+        let one = Value::Integer(1);
+        let inlined = inline_handler(Some(&one), &get(&one, "+:"), &[IR::Local(2)], 4, 8);
+        assert!(matches!(
+            inlined.as_deref(),
+            Some([
+                IR::Local(2),
+                IR::Constant(Value::Integer(1)),
+                IR::SendNative(_, 1)
+            ])
+        ));
+
+        // sends to self keep self & ivals
+        assert_eq!(
+            inline_handler(None, &get(&point, "x:"), &[IR::IVal(0)], 4, 8),
+            Some(vec![IR::IVal(0), IR::NewSelf(1)])
+        );
+        // locals are moved above the caller's stack, under the result
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "let:"), &[IR::int(2)], 4, 8),
+            Some(vec![IR::int(2), IR::Local(4), IR::Var(4), IR::SetVar])
+        );
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "lets:"), &[IR::Local(0)], 4, 8),
+            Some(vec![
+                IR::Local(0),
+                IR::int(1),
+                IR::Local(5),
+                IR::Local(4),
+                IR::send("+:", 1),
+                IR::Var(4),
+                IR::SetVar,
+                IR::Drop,
+            ])
+        );
+
+        // handlers with do params or complex args, or over budget, are sent
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "do:"), &[IR::Local(0)], 4, 8),
+            None
+        );
+        assert_eq!(
+            inline_handler(Some(&one), &get(&one, "+:"), &[IR::send("foo", 0)], 4, 8),
+            None
+        );
+        assert_eq!(
+            inline_handler(Some(&point), &get(&point, "double:"), &[IR::SelfRef], 4, 2),
+            None
+        );
+    }
+}
@@ -10,6 +10,7 @@ pub enum ParseError {
     Expected(String),
     ExpectedToken(Token),
     DuplicateKey(String),
+    DuplicateHandler(String),
     MixedKeyPair(String),
     WithSource(Box<ParseError>, Source),
     InContext(Box<ParseError>, SourceContext),
@@ -38,13 +39,28 @@ impl ParseError {
     }
 }
 
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Expected(name) => write!(f, "expected {}", name),
+            Self::ExpectedToken(token) => write!(f, "expected `{}`", token),
+            Self::DuplicateKey(key) => write!(f, "duplicate key `{}`", key),
+            Self::DuplicateHandler(selector) => write!(f, "duplicate handler `{{{}}}`", selector),
+            Self::MixedKeyPair(key) => write!(f, "cannot mix key `{}` with key-value pairs", key),
+            Self::WithSource(err, _) => write!(f, "{}", err),
+            Self::InContext(err, context) => write!(f, "{}\n{}", err, context),
+        }
+    }
+}
+
 pub type Parse<T> = Result<T, ParseError>;
 pub type ParseOpt<T> = Result<Option<T>, ParseError>;
 
 fn expect<T>(name: &str, value: ParseOpt<T>) -> Parse<T> {
     match value {
         Ok(Some(value)) => Ok(value),
-        _ => Err(ParseError::expected(name)),
+        Ok(None) => Err(ParseError::expected(name)),
+        Err(err) => Err(err),
     }
 }
 
@@ -101,11 +117,16 @@ pub struct Parser {
 impl Parser {
     pub fn parse(tokens: Vec<TokenWithSource>) -> Parse<Vec<Stmt>> {
         let mut parser = Self::new(tokens);
-        parser.program().map_err(|err| match parser.peek_source() {
+        parser.program().map_err(|err| match parser.error_source() {
             Some(source) => err.with_source(source),
             None => err,
         })
     }
+    // the token an error is at, or the end of the last token at the end of input
+    fn error_source(&self) -> Option<Source> {
+        self.peek_source()
+            .or_else(|| self.tokens.last().map(|t| t.source.end()))
+    }
     fn new(tokens: Vec<TokenWithSource>) -> Self {
         Self { tokens, index: 0 }
     }
@@ -116,7 +137,7 @@ impl Parser {
             .unwrap_or(Token::EndOfInput)
     }
     fn peek_source(&self) -> Option<Source> {
-        self.tokens.get(self.index).map(|t| t.source.clone())
+        self.tokens.get(self.index).map(|t| t.source)
     }
     fn advance(&mut self) {
         self.index += 1
@@ -164,8 +185,6 @@ impl Parser {
         Ok(out)
     }
 
-    ///
-
     fn key(&mut self) -> Parse<String> {
         let mut parts = vec![];
         while let Some(part) = self.peek().key_part() {
@@ -235,17 +254,27 @@ impl Parser {
                 self.advance();
                 Ok(Some(Expr::Integer(value)))
             }
+            Token::Float(value) => {
+                self.advance();
+                Ok(Some(Expr::Float(value)))
+            }
             Token::String(str) => {
                 self.advance();
                 Ok(Some(Expr::String(str)))
             }
-            Token::Identifier(value) => {
+            Token::StringStart(str) => self.interpolated_string(str).map(Some),
+            Token::Use => {
+                let source = self.peek_source();
                 self.advance();
-                Ok(Some(Expr::Identifier(value)))
+                self.expect_token(Token::OpenBrace)?;
+                let key = self.key()?;
+                self.expect_token(Token::CloseBrace)?;
+                Ok(Some(Expr::Use(key, source)))
             }
-            Token::QuotedIdentifier(value) => {
+            Token::Identifier(value) | Token::QuotedIdentifier(value) => {
+                let source = self.peek_source();
                 self.advance();
-                Ok(Some(Expr::Identifier(value)))
+                Ok(Some(Expr::Identifier(value, source)))
             }
             Token::OpenBracket => {
                 self.advance();
@@ -264,13 +293,14 @@ impl Parser {
             }
             Token::If => {
                 self.advance();
+                let source = self.peek_source();
                 let cond = expect("expr", self.expr())?;
                 self.expect_token(Token::Then)?;
                 let if_true = self.body()?;
                 match self.peek() {
                     Token::End => {
                         self.advance();
-                        Ok(Some(Expr::If(Box::new(cond), if_true, vec![])))
+                        Ok(Some(Expr::If(Box::new(cond), if_true, vec![], source)))
                     }
                     Token::Else => {
                         self.advance();
@@ -282,12 +312,13 @@ impl Parser {
                                     Box::new(cond),
                                     if_true,
                                     vec![Stmt::Expr(next)],
+                                    source,
                                 )))
                             }
                             _ => {
                                 let if_false = self.body()?;
                                 self.expect_token(Token::End)?;
-                                Ok(Some(Expr::If(Box::new(cond), if_true, if_false)))
+                                Ok(Some(Expr::If(Box::new(cond), if_true, if_false, source)))
                             }
                         }
                     }
@@ -309,10 +340,11 @@ impl Parser {
             loop {
                 match self.peek() {
                     Token::OpenBrace => {
+                        let source = self.peek_source();
                         self.advance();
                         let result = self.build_structure(|p| p.arg())?;
                         let args = result.items.into_iter().map(|p| p.1).collect();
-                        left = Expr::Send(result.selector, Box::new(left), args);
+                        left = Expr::Send(result.selector, Box::new(left), args, source);
 
                         self.expect_token(Token::CloseBrace)?;
                     }
@@ -326,28 +358,58 @@ impl Parser {
     fn unary_op_expr(&mut self) -> ParseOpt<Expr> {
         match self.peek() {
             Token::Operator(op) => {
+                let source = self.peek_source();
                 self.advance();
                 let expr = expect("expr", self.send_expr())?;
-                Ok(Some(Expr::Send(op, Box::new(expr), vec![])))
+                Ok(Some(Expr::Send(op, Box::new(expr), vec![], source)))
             }
             _ => self.send_expr(),
         }
     }
 
+    // "a${x}b" is parsed as ("a" ++ x) ++ "b"
+    fn interpolated_string(&mut self, start: String) -> Parse<Expr> {
+        let source = self.peek_source();
+        self.advance();
+        let mut out = Expr::String(start);
+        loop {
+            let expr = expect("expr", self.expr())?;
+            out = Expr::Send("++:".to_string(), Box::new(out), vec![expr], source);
+            let (str, done) = match self.peek() {
+                Token::StringMiddle(str) => (str, false),
+                Token::StringEnd(str) => (str, true),
+                _ => return Err(ParseError::expected("end of interpolation")),
+            };
+            self.advance();
+            if !str.is_empty() {
+                out = Expr::Send(
+                    "++:".to_string(),
+                    Box::new(out),
+                    vec![Expr::String(str)],
+                    source,
+                );
+            }
+            if done {
+                return Ok(out);
+            }
+        }
+    }
+
     fn expr(&mut self) -> ParseOpt<Expr> {
         if let Some(mut left) = self.unary_op_expr()? {
             loop {
                 match self.peek() {
                     Token::Operator(op) => {
+                        let source = self.peek_source();
                         self.advance();
                         let expr = expect("expr", self.unary_op_expr())?;
-                        left = Expr::Send(format!("{}:", op), Box::new(left), vec![expr]);
+                        left = Expr::Send(format!("{}:", op), Box::new(left), vec![expr], source);
                     }
                     Token::QuestionMark => {
                         self.advance();
-                        if let Expr::Send(selector, target, args) = left {
+                        if let Expr::Send(selector, target, args, source) = left {
                             let or_else = expect("expr", self.unary_op_expr())?;
-                            left = Expr::TrySend(selector, target, args, Box::new(or_else));
+                            left = Expr::TrySend(selector, target, args, Box::new(or_else), source);
                         } else {
                             return Err(ParseError::expected("try send"));
                         }
@@ -376,8 +438,9 @@ impl Parser {
     fn arg(&mut self) -> Parse<Expr> {
         match self.peek() {
             Token::Var => {
+                let source = self.peek_source();
                 self.advance();
-                Ok(Expr::VarArg(expect("var", self.ident())?))
+                Ok(Expr::VarArg(expect("var", self.ident())?, source))
             }
             Token::On | Token::OpenBrace => {
                 // object_body accepts On tokens
@@ -389,19 +452,17 @@ impl Parser {
 
     fn binding(&mut self) -> Parse<Binding> {
         match self.peek() {
-            Token::Identifier(key) => {
+            Token::Identifier(key) | Token::QuotedIdentifier(key) => {
+                let source = self.peek_source();
                 self.advance();
-                Ok(Binding::Identifier(key))
-            }
-            Token::QuotedIdentifier(key) => {
-                self.advance();
-                Ok(Binding::Identifier(key))
+                Ok(Binding::Identifier(key, source))
             }
             Token::OpenBracket => {
+                let source = self.peek_source();
                 self.advance();
                 let result = self.build_structure(|p| p.binding())?;
                 self.expect_token(Token::CloseBracket)?;
-                Ok(Binding::Destructure(result.items))
+                Ok(Binding::Destructure(result.items, source))
             }
             _ => Err(ParseError::expected("binding")),
         }
@@ -410,12 +471,16 @@ impl Parser {
     fn param(&mut self) -> Parse<Binding> {
         match self.peek() {
             Token::Var => {
+                let source = self.peek_source();
                 self.advance();
-                Ok(Binding::VarIdentifier(expect("var param", self.ident())?))
+                let name = expect("var param", self.ident())?;
+                Ok(Binding::VarIdentifier(name, source))
             }
             Token::Do => {
+                let source = self.peek_source();
                 self.advance();
-                Ok(Binding::DoIdentifier(expect("do param", self.ident())?))
+                let name = expect("do param", self.ident())?;
+                Ok(Binding::DoIdentifier(name, source))
             }
             _ => self.binding(),
         }
@@ -452,7 +517,7 @@ impl Parser {
                 self.advance();
                 let target = expect("set target", self.expr())?;
                 if self.expect_token(Token::ColonEquals).is_ok() {
-                    let binding = target.as_binding()?;
+                    let binding = target.into_binding()?;
                     let expr = expect("expr", self.expr())?;
                     Ok(Some(Stmt::Set(binding, expr)))
                 } else {
@@ -464,8 +529,9 @@ impl Parser {
                 self.advance();
                 let binding = self.binding()?;
                 self.expect_token(Token::ColonEquals)?;
+                let at = self.peek_source();
                 let source = self.import_source()?;
-                Ok(Some(Stmt::Import(binding, source, false)))
+                Ok(Some(Stmt::Import(binding, source, false, at)))
             }
             Token::Export => {
                 self.advance();
@@ -482,12 +548,23 @@ impl Parser {
                         self.advance();
                         let binding = self.binding()?;
                         self.expect_token(Token::ColonEquals)?;
+                        let at = self.peek_source();
                         let source = self.import_source()?;
-                        Ok(Some(Stmt::Import(binding, source, true)))
+                        Ok(Some(Stmt::Import(binding, source, true, at)))
                     }
                     _ => Err(ParseError::expected("export")),
                 }
             }
+            Token::Provide => {
+                self.advance();
+                self.expect_token(Token::OpenBrace)?;
+                let result = self.build_structure(|p| expect("expr", p.expr()))?;
+                self.expect_token(Token::CloseBrace)?;
+                if result.items.is_empty() {
+                    return Err(ParseError::expected("provide value"));
+                }
+                Ok(Some(Stmt::Provide(result.items)))
+            }
             Token::Return => {
                 self.advance();
                 if let Some(expr) = self.expr()? {
@@ -570,13 +647,63 @@ mod test {
         assert_ok(
             vec![Let, ident("x"), ColonEquals, Integer(123)],
             vec![Stmt::Let(
-                Binding::Identifier("x".to_string()),
+                Binding::Identifier("x".to_string(), Some(Source::new(0, 0))),
                 Expr::Integer(123),
                 false,
             )],
         )
     }
 
+    #[test]
+    fn interpolated_string() {
+        let send = |target, arg| {
+            Expr::Send(
+                "++:".to_string(),
+                Box::new(target),
+                vec![arg],
+                Some(Source::new(0, 0)),
+            )
+        };
+        assert_ok(
+            vec![
+                StringStart("a".to_string()),
+                Integer(1),
+                StringMiddle("".to_string()),
+                Integer(2),
+                StringEnd("b".to_string()),
+            ],
+            vec![Stmt::Expr(send(
+                send(
+                    send(Expr::String("a".to_string()), Expr::Integer(1)),
+                    Expr::Integer(2),
+                ),
+                Expr::String("b".to_string()),
+            ))],
+        )
+    }
+
+    #[test]
+    fn provide_use() {
+        assert_ok(
+            vec![
+                Provide,
+                OpenBrace,
+                ident("x"),
+                Colon,
+                Integer(1),
+                CloseBrace,
+                Use,
+                OpenBrace,
+                ident("x"),
+                CloseBrace,
+            ],
+            vec![
+                Stmt::Provide(vec![("x".to_string(), Expr::Integer(1))]),
+                Stmt::Expr(Expr::Use("x".to_string(), Some(Source::new(0, 0)))),
+            ],
+        )
+    }
+
     #[test]
     fn unexpected_end_of_input() {
         assert_err(
@@ -589,4 +716,19 @@ mod test {
     fn expected_end_of_input() {
         assert_err(vec![CloseParen], ParseError::ExpectedToken(EndOfInput))
     }
+
+    #[test]
+    fn duplicate_handler() {
+        let handler = [On, OpenBrace, ident("x"), CloseBrace, Integer(1)];
+        assert_err(
+            [
+                vec![OpenBracket],
+                handler.to_vec(),
+                handler.to_vec(),
+                vec![CloseBracket],
+            ]
+            .concat(),
+            ParseError::DuplicateHandler("x".to_string()),
+        )
+    }
 }
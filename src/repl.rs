@@ -0,0 +1,158 @@
+use std::io::{BufRead, Write};
+
+use crate::{
+    compiler::Compiler,
+    grammar::{SourceFile, Token},
+    interrupt_on_ctrl_c,
+    lexer::{LexError, Lexer},
+    load_modules,
+    parser::Parser,
+    runtime::{Interpreter, Interrupt, Limits, ModuleLoader},
+    Error, COMPILER_FLAGS,
+};
+
+pub struct Repl<'a> {
+    compiler: Compiler,
+    interpreter: Interpreter<'a>,
+    interrupt: Interrupt,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(modules: &'a mut ModuleLoader) -> Self {
+        let interrupt = Interrupt::new();
+        Repl {
+            compiler: Compiler::new(COMPILER_FLAGS),
+            interpreter: Interpreter::new(modules, Limits::default())
+                .with_interrupt(interrupt.clone()),
+            interrupt,
+        }
+    }
+    // stops the input being evaluated
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+    // returns the debug representation of the last statement
+    pub fn eval(&mut self, code: &str) -> Result<String, Error> {
+        let tokens = Lexer::lex(code).map_err(|err| Error::Lex(err.in_context(code)))?;
+        let ast = Parser::parse(tokens).map_err(|err| Error::Parse(err.in_context(code)))?;
+        let locals = self.compiler.root_locals();
+        self.compiler.set_file(SourceFile::new("<repl>", code));
+        let ir = match self.compiler.program(ast) {
+            Ok(ir) => ir,
+            Err(err) => {
+                self.compiler.restore_root_locals(locals);
+                return Err(Error::Compile(err.in_context(code)));
+            }
+        };
+        let stack_size = self.compiler.root_stack_size();
+        self.interrupt.reset();
+        match self.interpreter.eval(ir, stack_size) {
+            Ok(value) => Ok(value.debug()),
+            Err(err) => {
+                self.compiler.restore_root_locals(locals);
+                Err(Error::Runtime(err))
+            }
+        }
+    }
+}
+
+// input is incomplete while brackets, `if ... end` blocks or strings are left open
+pub fn is_incomplete(code: &str) -> bool {
+    let tokens = match Lexer::lex(code) {
+        Ok(tokens) => tokens,
+        Err(LexError::WithSource(err, _)) => return *err == LexError::UnterminatedString,
+        // other lex errors are reported when the input is evaluated
+        Err(_) => return false,
+    };
+    let mut depth = 0;
+    let mut prev = Token::EndOfInput;
+    for token in tokens {
+        match token.token {
+            Token::OpenBrace | Token::OpenBracket | Token::OpenParen => depth += 1,
+            Token::CloseBrace | Token::CloseBracket | Token::CloseParen | Token::End => depth -= 1,
+            // `else if` shares the `end` of the enclosing `if`
+            Token::If if prev != Token::Else => depth += 1,
+            _ => {}
+        }
+        prev = token.token;
+    }
+    depth > 0
+}
+
+pub fn repl() -> Result<(), Error> {
+    let mut modules = load_modules(None);
+    let mut repl = Repl::new(&mut modules);
+    interrupt_on_ctrl_c(repl.interrupt());
+    let stdin = std::io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "| " });
+        std::io::stdout().flush().ok();
+
+        let mut line = String::new();
+        let read = stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|err| Error::Io("stdin".to_string(), err))?;
+        if read == 0 {
+            println!();
+            return Ok(());
+        }
+        // Ctrl-C at the prompt discards the unfinished input
+        if repl.interrupt.is_interrupted() {
+            repl.interrupt.reset();
+            input.clear();
+        }
+        input.push_str(&line);
+        if is_incomplete(&input) {
+            continue;
+        }
+
+        match repl.eval(&input) {
+            Ok(result) => println!("{}", result),
+            Err(err) => eprintln!("{}", err),
+        }
+        input.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::STDLIB;
+
+    #[test]
+    fn bindings_persist() {
+        let mut modules = STDLIB.with(|m| m.clone());
+        let mut repl = Repl::new(&mut modules);
+        repl.eval("import [_Option_] := \"core\"").unwrap();
+        repl.eval("let x := Option{some: 1}").unwrap();
+        repl.eval("var y := 2").unwrap();
+        assert!(repl.eval("let z := x{foo}").is_err());
+        assert!(repl.eval("z").is_err());
+        repl.eval("set y := y + 1").unwrap();
+        assert_eq!(repl.eval("x{some!} + y").unwrap(), "4");
+    }
+
+    #[test]
+    fn provided_values_persist() {
+        let mut modules = STDLIB.with(|m| m.clone());
+        let mut repl = Repl::new(&mut modules);
+        repl.eval("provide{x: 1}").unwrap();
+        assert!(repl.eval("provide{x: 2}\n1{foo}").is_err());
+        assert_eq!(repl.eval("use{x}").unwrap(), "1");
+        repl.eval("provide{x: 3}").unwrap();
+        assert_eq!(repl.eval("use{x}").unwrap(), "3");
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("let x := [\n"));
+        assert!(is_incomplete("if x then\n  1\nelse if y then\n"));
+        assert!(!is_incomplete("if x then 1 else if y then 2 end"));
+        assert!(is_incomplete("let s := \"abc"));
+        assert!(!is_incomplete("# [\n1"));
+        assert!(is_incomplete("let s := \"${x{\n"));
+        assert!(!is_incomplete("let s := \"\\\"[\""));
+    }
+}
@@ -1,6 +1,17 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use crate::ir::{Address, Handler, Selector, Value, IR};
+use crate::{
+    grammar::Position,
+    ir::{Address, Handler, Program, Selector, Value, IR},
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum RuntimeError {
@@ -10,62 +21,291 @@ pub enum RuntimeError {
     ExpectedType(String),
     ModuleLoadLoop(String),
     UnknownModule(String),
-    Panic(String),
-    WithStackTrace(Box<RuntimeError>, Vec<String>),
+    InvalidModule(String, String),
+    Panic(Value),
+    TypeMismatch(String, String),
+    IndexOutOfRange(i64, usize),
+    DivisionByZero,
+    InvalidCharCode(i64),
+    NoProvider(String),
+    StackOverflow(usize),
+    OutOfFuel,
+    Interrupted,
+    AssertionFailed(Value, Value, Option<Position>),
+    WithStackTrace(Box<RuntimeError>, Vec<TraceEntry>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub selector: Option<Selector>,
+    pub position: Option<Position>,
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.position {
+            Some(position) => write!(f, "{} ", position)?,
+            None => write!(f, "<native> ")?,
+        }
+        match &self.selector {
+            Some(selector) => write!(f, "{{{}}}", selector),
+            None => write!(f, "<root>"),
+        }
+    }
 }
 impl RuntimeError {
     #[cfg(test)]
-    fn base_error(self) -> RuntimeError {
+    pub fn base_error(self) -> RuntimeError {
         match self {
             Self::WithStackTrace(err, _) => err.base_error(),
             err => err,
         }
     }
+    // the value a catch block is called with. Only panics are caught; other errors are bugs in
+    // the program (or limits on it) & stop it with a stack trace.
+    fn catch_payload(&self) -> Option<Value> {
+        match self {
+            Self::Panic(payload) => Some(payload.clone()),
+            Self::WithStackTrace(err, _) => err.catch_payload(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DoesNotUnderstand(selector) => {
+                write!(f, "does not understand message {{{}}}", selector)
+            }
+            Self::ExpectedVarArg => write!(f, "expected var arg"),
+            Self::DidNotExpectDoArg => write!(f, "did not expect do arg"),
+            Self::ExpectedType(name) => write!(f, "expected {}", name),
+            Self::ModuleLoadLoop(name) => write!(f, "import cycle loading module \"{}\"", name),
+            Self::UnknownModule(name) => write!(f, "unknown module \"{}\"", name),
+            Self::InvalidModule(path, message) => write!(f, "in module {}: {}", path, message),
+            Self::Panic(Value::String(message)) => write!(f, "panic: {}", message),
+            Self::Panic(payload) => write!(f, "panic: {}", payload.debug()),
+            Self::TypeMismatch(expected, received) => {
+                write!(f, "expected {}, received {}", expected, received)
+            }
+            Self::IndexOutOfRange(index, length) => {
+                write!(f, "index {} out of range for length {}", index, length)
+            }
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::InvalidCharCode(code) => write!(f, "invalid char code {}", code),
+            Self::NoProvider(key) => write!(f, "no value provided for `{}`", key),
+            Self::StackOverflow(depth) => write!(f, "stack overflow at depth {}", depth),
+            Self::OutOfFuel => write!(f, "out of fuel"),
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::AssertionFailed(expected, received, position) => {
+                match position {
+                    Some(position) => write!(f, "assertion failed at {}", position)?,
+                    None => write!(f, "assertion failed")?,
+                }
+                write!(f, "\n  expected: {}", expected.debug())?;
+                write!(f, "\n  received: {}", received.debug())?;
+                for (key, expected, received) in expected.frame_diff(received).unwrap_or_default() {
+                    write!(
+                        f,
+                        "\n    {}: expected {}, received {}",
+                        key,
+                        expected.debug(),
+                        received.debug()
+                    )?;
+                }
+                Ok(())
+            }
+            Self::WithStackTrace(err, trace) => {
+                write!(f, "{}", err)?;
+                for entry in trace.iter().rev() {
+                    write!(f, "\n  at {}", entry)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 pub type Runtime<T> = Result<T, RuntimeError>;
 
+// exceeding either limit raises a stack overflow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_frames: usize,
+    pub max_stack: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frames: 100_000,
+            max_stack: 1_000_000,
+        }
+    }
+}
+
+// stops a running program from another thread, e.g. a Ctrl-C handler
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// shared with the interpreters that load modules, so imports spend the same fuel
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    // instructions left to run, or None for no limit
+    fuel: Option<u64>,
+    interrupt: Interrupt,
+}
+
+// stack overflow traces only show the innermost frames
+const OVERFLOW_TRACE_LEN: usize = 20;
+
 #[derive(Debug, Clone)]
 enum ModuleLoadState {
-    Init(Vec<IR>),
+    Init(Program),
     Loading,
     Ready(Value),
 }
 
+// compiles module source, rendering errors as a message
+pub type CompileModule = fn(&Path, &str) -> Result<Program, String>;
+
+#[derive(Debug, Clone)]
+struct FileResolver {
+    compile: CompileModule,
+    roots: Vec<PathBuf>,
+    // the path of the program being run, which its relative imports are resolved against
+    entry: Option<PathBuf>,
+}
+
+impl FileResolver {
+    // "./foo" & "../foo" are relative to the entry program, absolute paths (which relative
+    // imports in module files are compiled to) are used as is, & other paths are in the roots
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let file_name = if Path::new(name).extension().is_some() {
+            name.to_string()
+        } else {
+            format!("{}.gob", name)
+        };
+        if Path::new(name).is_absolute() {
+            return std::fs::canonicalize(file_name).ok();
+        }
+        if name.starts_with("./") || name.starts_with("../") {
+            let dir = self
+                .entry
+                .as_ref()
+                .and_then(|path| path.parent())
+                .map(|dir| dir.to_path_buf())
+                .unwrap_or_default();
+            return std::fs::canonicalize(dir.join(file_name)).ok();
+        }
+        self.roots
+            .iter()
+            .find_map(|root| std::fs::canonicalize(root.join(&file_name)).ok())
+    }
+    fn compile(&self, path: &Path) -> Runtime<Program> {
+        let display = path.display().to_string();
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| RuntimeError::InvalidModule(display.clone(), err.to_string()))?;
+        (self.compile)(path, &source)
+            .map_err(|message| RuntimeError::InvalidModule(display, message))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ModuleLoader {
     modules: HashMap<String, ModuleLoadState>,
+    files: Option<FileResolver>,
 }
 
 impl ModuleLoader {
     pub fn new() -> Self {
         ModuleLoader {
             modules: HashMap::new(),
+            files: None,
         }
     }
-    pub fn add_init(&mut self, name: &str, ir: Vec<IR>) {
+    pub fn add_init(&mut self, name: &str, program: impl Into<Program>) {
         self.modules
-            .insert(name.to_string(), ModuleLoadState::Init(ir));
+            .insert(name.to_string(), ModuleLoadState::Init(program.into()));
     }
     pub fn add_ready(&mut self, name: &str, value: Value) {
         self.modules
             .insert(name.to_string(), ModuleLoadState::Ready(value));
     }
-    pub fn load(&mut self, name: &str) -> Runtime<Value> {
+    // resolve unregistered modules to .gob files, compiled on first load
+    pub fn add_file_resolver(&mut self, compile: CompileModule, roots: Vec<PathBuf>) {
+        self.files = Some(FileResolver {
+            compile,
+            roots,
+            entry: None,
+        });
+    }
+    // the path of the program being run, which relative imports are resolved against
+    pub fn set_entry_path(&mut self, path: &Path) {
+        if let Some(files) = &mut self.files {
+            files.entry = Some(path.to_path_buf());
+        }
+    }
+    pub fn load(&mut self, name: &str, limits: Limits, budget: &mut Budget) -> Runtime<Value> {
+        if !self.modules.contains_key(name) {
+            if let Some(path) = self.files.as_ref().and_then(|files| files.resolve(name)) {
+                return self.load_file(path, limits, budget);
+            }
+        }
+        self.load_registered(name, limits, budget)
+    }
+    fn load_file(&mut self, path: PathBuf, limits: Limits, budget: &mut Budget) -> Runtime<Value> {
+        let key = path.display().to_string();
+        if !self.modules.contains_key(&key) {
+            let ir = self.files.as_ref().unwrap().compile(&path)?;
+            self.add_init(&key, ir);
+        }
+        self.load_registered(&key, limits, budget)
+    }
+    fn load_registered(
+        &mut self,
+        name: &str,
+        limits: Limits,
+        budget: &mut Budget,
+    ) -> Runtime<Value> {
         match self.modules.get_mut(name) {
             Some(ModuleLoadState::Loading) => Err(RuntimeError::ModuleLoadLoop(name.to_string())),
             Some(ModuleLoadState::Ready(value)) => Ok(value.clone()),
-            Some(ModuleLoadState::Init(ir)) => {
-                let ir = std::mem::take(ir);
+            Some(ModuleLoadState::Init(program)) => {
+                let program = std::mem::take(program);
                 self.modules
                     .insert(name.to_string(), ModuleLoadState::Loading);
 
-                match Interpreter::program(ir, self) {
+                let mut interpreter = Interpreter::new(self, limits);
+                interpreter.budget = std::mem::take(budget);
+                let result = interpreter.run_program(program.clone());
+                *budget = interpreter.budget;
+                match result {
                     Ok(value) => {
                         self.add_ready(name, value.clone());
                         Ok(value)
                     }
-                    Err(err) => Err(err),
+                    // a failed module is run again the next time it is imported
+                    Err(err) => {
+                        self.add_init(name, program);
+                        Err(err)
+                    }
                 }
             }
             None => Err(RuntimeError::UnknownModule(name.to_string())),
@@ -73,10 +313,13 @@ impl ModuleLoader {
     }
 }
 
+type Provided = Vec<(String, Value)>;
+
 enum Frame {
     Root {
-        body: Vec<IR>,
+        program: Program,
         ip: usize,
+        provided: Provided,
     },
     Handler {
         handler: Rc<Handler>,
@@ -85,12 +328,19 @@ enum Frame {
         self_value: Value,
         target_value: Value,
         return_from_index: usize,
+        provided: Provided,
+        // the send that called the handler, if it replaced the frame the send was in
+        tail_call_site: Option<Position>,
     },
 }
 
 impl Frame {
-    fn root(code: Vec<IR>) -> Self {
-        Frame::Root { body: code, ip: 0 }
+    fn root(program: Program) -> Self {
+        Frame::Root {
+            program,
+            ip: 0,
+            provided: vec![],
+        }
     }
     fn local_offset(&self) -> usize {
         match self {
@@ -104,19 +354,20 @@ impl Frame {
             Frame::Handler { self_value, .. } => self_value.clone(),
         }
     }
-    fn ival(&self, index: usize) -> Value {
+    fn ival(&self, index: usize) -> Runtime<Value> {
         match self {
-            Frame::Root { .. } => panic!("root has no ivals"),
+            // root has no ivals
+            Frame::Root { .. } => Value::Unit.ival(index),
             Frame::Handler { target_value, .. } => target_value.ival(index),
         }
     }
     fn next(&mut self) -> NextResult {
         match self {
-            Frame::Root { body, ip } => {
-                if *ip >= body.len() {
+            Frame::Root { program, ip, .. } => {
+                if *ip >= program.body.len() {
                     return NextResult::Done;
                 }
-                let res = NextResult::IR(body[*ip].clone());
+                let res = NextResult::IR(program.body.clone(), *ip);
                 *ip += 1;
                 res
             }
@@ -129,12 +380,26 @@ impl Frame {
                 if *ip >= handler.body.len() {
                     return NextResult::Return(*local_offset);
                 }
-                let res = NextResult::IR(handler.body[*ip].clone());
+                let res = NextResult::IR(handler.body.clone(), *ip);
                 *ip += 1;
                 res
             }
         }
     }
+    // the instruction being evaluated is the last one in a handler without vars, or only
+    // jumps to the end of the handler follow it
+    fn is_tail_position(&self) -> bool {
+        match self {
+            Frame::Root { .. } => false,
+            Frame::Handler { handler, ip, .. } => {
+                let mut ip = *ip;
+                while let Some(IR::Jump(index)) = handler.body.get(ip) {
+                    ip = *index;
+                }
+                ip >= handler.body.len() && !handler.has_vars
+            }
+        }
+    }
     fn return_from_index(&self) -> usize {
         match self {
             Frame::Root { .. } => 0,
@@ -143,56 +408,205 @@ impl Frame {
             } => *return_from_index,
         }
     }
-    fn do_loop(&mut self) {
+    fn jump(&mut self, index: usize) {
         match self {
-            Frame::Root { ip, .. } => *ip = 0,
-            Frame::Handler { ip, .. } => *ip = 0,
+            Frame::Root { ip, .. } => *ip = index,
+            Frame::Handler { ip, .. } => *ip = index,
         }
     }
-    fn trace(&self) -> &str {
+    // position of the instruction being evaluated
+    fn position(&self) -> Option<&Position> {
         match self {
-            Frame::Root { .. } => "<root>",
-            Frame::Handler { handler, .. } => &handler.selector,
+            Frame::Root { program, ip, .. } => program.positions.get(ip.checked_sub(1)?),
+            Frame::Handler { handler, ip, .. } => handler.positions.get(ip.checked_sub(1)?),
         }
     }
+    fn provided(&self) -> &Provided {
+        match self {
+            Frame::Root { provided, .. } => provided,
+            Frame::Handler { provided, .. } => provided,
+        }
+    }
+    fn provided_mut(&mut self) -> &mut Provided {
+        match self {
+            Frame::Root { provided, .. } => provided,
+            Frame::Handler { provided, .. } => provided,
+        }
+    }
+    // the frame's entry, then one for each handler inlined at the current instruction
+    fn trace(&self) -> Vec<TraceEntry> {
+        let mut entries = vec![TraceEntry {
+            selector: match self {
+                Frame::Root { .. } => None,
+                Frame::Handler { handler, .. } => Some(handler.selector),
+            },
+            position: self.position().cloned(),
+        }];
+        let mut inlined = self
+            .position()
+            .and_then(|position| position.inlined.clone());
+        while let Some(handler) = inlined {
+            inlined = handler.position.as_ref().and_then(|p| p.inlined.clone());
+            entries.push(TraceEntry {
+                selector: Some(handler.selector),
+                position: handler.position.clone(),
+            });
+        }
+        entries
+    }
 }
 
 enum NextState {
     Init,
     Return,
+    // resume a return that was interrupted by an ensure block
+    ReturnFrom(usize),
 }
 
 enum NextResult {
-    IR(IR),
+    // the body of the frame & the index of the instruction to evaluate
+    IR(Rc<[IR]>, usize),
     Return(usize),
     Done,
 }
 
+// native handlers in progress that panics & returns unwind through
+enum Unwinder {
+    // `catch:try:`: panics unwind to the frame at `frame_index` and call `handler`
+    Catch {
+        frame_index: usize,
+        stack_size: usize,
+        handler: Value,
+    },
+    // `do:ensure:`: panics & returns unwind to the frame at `frame_index`, which runs its
+    // ensure block and then resumes unwinding
+    Ensure {
+        frame_index: usize,
+        stack_size: usize,
+    },
+}
+
+impl Unwinder {
+    fn frame_index(&self) -> usize {
+        match self {
+            Unwinder::Catch { frame_index, .. } => *frame_index,
+            Unwinder::Ensure { frame_index, .. } => *frame_index,
+        }
+    }
+}
+
+// a return or error waiting for an ensure block to finish
+enum Unwinding {
+    Return(usize),
+    Error(RuntimeError, Vec<TraceEntry>),
+}
+
 pub struct Interpreter<'a> {
     stack: Vec<Value>,
     frames: Vec<Frame>,
+    unwinders: Vec<Unwinder>,
+    // keyed by the frame running the ensure block
+    unwinding: Vec<(usize, Unwinding)>,
     next_state: NextState,
     modules: &'a mut ModuleLoader,
+    limits: Limits,
+    budget: Budget,
+    // values provided at the root, kept between evals
+    root_provided: Provided,
+    // the trace of an error resumed after an ensure block, from where it was raised
+    resumed_trace: Option<Vec<TraceEntry>>,
 }
 
 impl<'a> Interpreter<'a> {
-    pub fn program(code: Vec<IR>, modules: &'a mut ModuleLoader) -> Runtime<Value> {
-        let mut interpreter = Interpreter {
+    pub fn new(modules: &'a mut ModuleLoader, limits: Limits) -> Self {
+        Interpreter {
             stack: Vec::with_capacity(1024),
-            frames: {
-                let mut frames = Vec::with_capacity(64);
-                frames.push(Frame::root(code));
-                frames
-            },
+            frames: Vec::with_capacity(64),
+            unwinders: vec![],
+            unwinding: vec![],
             next_state: NextState::Init,
             modules,
-        };
-        interpreter.run()
+            limits,
+            budget: Budget::default(),
+            root_provided: vec![],
+            resumed_trace: None,
+        }
+    }
+    // fails with OutOfFuel after running `fuel` more instructions
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.budget.fuel = Some(fuel);
+        self
+    }
+    pub fn with_interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.budget.interrupt = interrupt;
+        self
+    }
+    pub fn fuel(&self) -> Option<u64> {
+        self.budget.fuel
+    }
+    pub fn program(
+        code: impl Into<Program>,
+        modules: &'a mut ModuleLoader,
+        limits: Limits,
+    ) -> Runtime<Value> {
+        Interpreter::new(modules, limits).run_program(code.into())
+    }
+    // returns the result & the fuel left over
+    pub fn program_with_fuel(
+        code: impl Into<Program>,
+        modules: &'a mut ModuleLoader,
+        limits: Limits,
+        fuel: u64,
+    ) -> (Runtime<Value>, u64) {
+        let mut interpreter = Interpreter::new(modules, limits).with_fuel(fuel);
+        let result = interpreter.run_program(code.into());
+        (result, interpreter.fuel().unwrap_or_default())
+    }
+    pub fn run_program(&mut self, code: Program) -> Runtime<Value> {
+        self.frames.push(Frame::root(code));
+        self.run()
+    }
+    // run code at the root, keeping the first `stack_size` values on the stack as locals
+    // & the values it provides for the next call. Both are restored if the code fails.
+    pub fn eval(&mut self, code: Program, stack_size: usize) -> Runtime<Value> {
+        let prev_stack_size = self.stack.len();
+        self.frames.push(Frame::Root {
+            program: code,
+            ip: 0,
+            provided: self.root_provided.clone(),
+        });
+        let result = self.run();
+        if let (Ok(_), Some(Frame::Root { provided, .. })) = (&result, self.frames.first_mut()) {
+            self.root_provided = std::mem::take(provided);
+        }
+        self.frames.clear();
+        self.unwinders.clear();
+        self.unwinding.clear();
+        self.next_state = NextState::Init;
+        match result {
+            Ok(value) => {
+                // an early return at the root can skip bindings
+                self.stack.resize(stack_size, Value::Unit);
+                Ok(value)
+            }
+            Err(err) => {
+                self.stack.truncate(prev_stack_size);
+                Err(err)
+            }
+        }
     }
     fn run(&mut self) -> Runtime<Value> {
         loop {
             match self.next() {
-                NextResult::IR(ir) => ir.eval(self).map_err(|err| self.add_trace(err))?,
+                NextResult::IR(body, index) => {
+                    let result = match self.tick() {
+                        Ok(()) => body[index].eval(self),
+                        err => err,
+                    };
+                    if let Err(err) = result {
+                        self.catch(err)?;
+                    }
+                }
                 NextResult::Return(offset) => {
                     let value = self.pop();
                     self.stack.truncate(offset);
@@ -202,21 +616,116 @@ impl<'a> Interpreter<'a> {
             };
         }
     }
+    // spends an instruction's fuel, or stops if out of fuel or interrupted
+    fn tick(&mut self) -> Runtime<()> {
+        if let Some(fuel) = &mut self.budget.fuel {
+            if *fuel == 0 {
+                return Err(RuntimeError::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+        if self.budget.interrupt.is_interrupted() {
+            return Err(RuntimeError::Interrupted);
+        }
+        Ok(())
+    }
+    // unwinds an error to the innermost catch or ensure, or fails with a stack trace
+    fn catch(&mut self, error: RuntimeError) -> Runtime<()> {
+        let trace = match self.resumed_trace.take() {
+            Some(trace) => trace,
+            None => self.stack_trace(&error),
+        };
+        let Some(payload) = error.catch_payload() else {
+            return Err(RuntimeError::WithStackTrace(Box::new(error), trace));
+        };
+        self.next_state = NextState::Init;
+        match self.unwinders.pop() {
+            Some(Unwinder::Catch {
+                frame_index,
+                stack_size,
+                handler,
+            }) => {
+                self.unwind_to(frame_index, stack_size);
+                self.push(payload);
+                self.send(Selector::COLON, handler, 1)
+                    .map_err(|err| self.add_trace(err))
+            }
+            Some(Unwinder::Ensure {
+                frame_index,
+                stack_size,
+            }) => {
+                self.unwind_to(frame_index, stack_size);
+                // in place of the do block's result
+                self.push(Value::Unit);
+                self.unwinding
+                    .push((frame_index, Unwinding::Error(error, trace)));
+                Ok(())
+            }
+            None => Err(RuntimeError::WithStackTrace(Box::new(error), trace)),
+        }
+    }
+    fn unwind_to(&mut self, frame_index: usize, stack_size: usize) {
+        self.frames.truncate(frame_index + 1);
+        self.stack.truncate(stack_size);
+        // unwinds interrupted in frames that no longer exist are abandoned
+        self.unwinding.retain(|(index, _)| *index <= frame_index);
+    }
+    // if a return to the frame at `return_from_index` would skip an ensure block,
+    // unwinds to it instead & resumes the return afterwards
+    fn ensure_before_return(&mut self, return_from_index: usize) -> bool {
+        while let Some(unwinder) = self.unwinders.last() {
+            if unwinder.frame_index() < return_from_index {
+                return false;
+            }
+            if let Some(Unwinder::Ensure {
+                frame_index,
+                stack_size,
+            }) = self.unwinders.pop()
+            {
+                let value = self.pop();
+                self.unwind_to(frame_index, stack_size);
+                self.push(value);
+                self.unwinding
+                    .push((frame_index, Unwinding::Return(return_from_index)));
+                return true;
+            }
+        }
+        false
+    }
     fn add_trace(&self, error: RuntimeError) -> RuntimeError {
-        let stack_trace = self.frames.iter().map(|f| f.trace().to_string()).collect();
+        let stack_trace = self.stack_trace(&error);
         RuntimeError::WithStackTrace(Box::new(error), stack_trace)
     }
+    fn stack_trace(&self, error: &RuntimeError) -> Vec<TraceEntry> {
+        let skip = match error {
+            RuntimeError::StackOverflow(_) => self.frames.len().saturating_sub(OVERFLOW_TRACE_LEN),
+            _ => 0,
+        };
+        self.frames
+            .iter()
+            .skip(skip)
+            .flat_map(|f| f.trace())
+            .collect()
+    }
     fn next(&mut self) -> NextResult {
-        if let NextState::Return = self.next_state {
+        let return_from_index = match self.next_state {
+            NextState::Init => None,
+            NextState::Return => Some(self.return_from_index()),
+            NextState::ReturnFrom(index) => Some(index),
+        };
+        if let Some(return_from_index) = return_from_index {
             self.next_state = NextState::Init;
-            let return_from_index = self.return_from_index();
-            if return_from_index == 0 {
-                return NextResult::Done;
+            if !self.ensure_before_return(return_from_index) {
+                if return_from_index == 0 {
+                    return NextResult::Done;
+                }
+                self.frames.truncate(return_from_index + 1);
+                self.unwinding
+                    .retain(|(index, _)| *index < return_from_index);
+                let last_frame = self.frames.pop().unwrap();
+                let offset = last_frame.local_offset();
+                return NextResult::Return(offset);
             }
-            self.frames.truncate(return_from_index + 1);
-            let last_frame = self.frames.pop().unwrap();
-            let offset = last_frame.local_offset();
-            return NextResult::Return(offset);
         }
 
         let frame = self.top_mut();
@@ -238,78 +747,119 @@ impl<'a> Interpreter<'a> {
         target: Value,
         arity: usize,
     ) -> Runtime<()> {
-        let local_offset = self.stack.len();
+        let local_offset = self.stack.len() - arity;
         for (i, param) in handler.params.iter().enumerate() {
-            param.check_arg(&self.stack[local_offset - arity + i])?;
+            param.check_arg(&self.stack[local_offset + i])?;
         }
-        match target {
-            Value::DoObject(_, return_from_index, ref self_value) => {
-                self.frames.push(Frame::Handler {
-                    handler,
-                    ip: 0,
-                    local_offset: local_offset - arity,
-                    self_value: *self_value.clone(),
-                    target_value: target,
-                    return_from_index,
-                })
+        self.push_frame(handler, target, local_offset)
+    }
+    pub fn send(&mut self, selector: Selector, target: Value, arity: usize) -> Runtime<()> {
+        let class = target.class()?;
+        let handler = class.get(selector)?;
+        self.send_direct(handler, target, arity)
+    }
+    fn push_frame(
+        &mut self,
+        handler: Rc<Handler>,
+        target: Value,
+        local_offset: usize,
+    ) -> Runtime<()> {
+        let mut local_offset = local_offset;
+        let mut provided = vec![];
+        let mut tail_call_site = None;
+        // a send that ends a handler replaces its frame, so recursion runs in constant space.
+        // Do objects that return from the replaced frame return from its replacement instead,
+        // which returns to the same caller.
+        let is_tail_call = self.top().is_tail_position();
+        let frames = self.frames.len() - usize::from(is_tail_call);
+        if frames >= self.limits.max_frames || self.stack.len() > self.limits.max_stack {
+            return Err(RuntimeError::StackOverflow(self.frames.len()));
+        }
+        if is_tail_call {
+            tail_call_site = self.top().position().cloned();
+            if let Some(Frame::Handler {
+                local_offset: offset,
+                provided: prev_provided,
+                ..
+            }) = self.frames.pop()
+            {
+                self.stack.drain(offset..local_offset);
+                local_offset = offset;
+                provided = prev_provided;
             }
-            _ => {
-                let return_from_index = self.frames.len();
-                self.frames.push(Frame::Handler {
-                    handler,
-                    ip: 0,
-                    local_offset: local_offset - arity,
-                    self_value: target.clone(),
-                    target_value: target,
-                    return_from_index,
-                })
+        }
+        let (self_value, return_from_index) = match target {
+            Value::DoObject(_, return_from_index, ref self_value) => {
+                (*self_value.clone(), return_from_index)
             }
+            _ => (target.clone(), self.frames.len()),
         };
+        self.frames.push(Frame::Handler {
+            handler,
+            ip: 0,
+            local_offset,
+            self_value,
+            target_value: target,
+            return_from_index,
+            provided,
+            tail_call_site,
+        });
         Ok(())
     }
-    pub fn send(&mut self, selector: &str, target: Value, arity: usize) -> Runtime<()> {
-        let class = target.class();
-        let handler = class.get(selector)?;
-        let local_offset = self.stack.len();
-        for (i, param) in handler.params.iter().enumerate() {
-            param.check_arg(&self.stack[local_offset - arity + i])?;
+    // calls f, unwinding to here and calling handler with the payload if it panics
+    pub fn try_send(&mut self, f: Value, handler: Value) -> Runtime<()> {
+        self.unwinders.push(Unwinder::Catch {
+            frame_index: self.frames.len() - 1,
+            stack_size: self.stack.len(),
+            handler,
+        });
+        self.send(Selector::CALL, f, 0)
+    }
+    // calls f, unwinding to here if it returns early or panics
+    pub fn ensure_send(&mut self, f: Value) -> Runtime<()> {
+        self.unwinders.push(Unwinder::Ensure {
+            frame_index: self.frames.len() - 1,
+            stack_size: self.stack.len(),
+        });
+        self.send(Selector::CALL, f, 0)
+    }
+    // f returned normally; unwinding has already removed its unwinder otherwise
+    pub fn end_unwinder(&mut self) {
+        if let Some(unwinder) = self.unwinders.last() {
+            if unwinder.frame_index() == self.frames.len() - 1 {
+                self.unwinders.pop();
+            }
         }
-        match target {
-            Value::DoObject(_, return_from_index, ref self_value) => {
-                self.frames.push(Frame::Handler {
-                    handler,
-                    ip: 0,
-                    local_offset: local_offset - arity,
-                    self_value: *self_value.clone(),
-                    target_value: target,
-                    return_from_index,
-                })
+    }
+    // continues the return or error that was interrupted to run the current ensure block
+    pub fn resume_unwinding(&mut self) -> Runtime<()> {
+        match self.unwinding.last() {
+            Some((index, _)) if *index == self.frames.len() - 1 => {}
+            _ => return Ok(()),
+        }
+        match self.unwinding.pop().unwrap().1 {
+            Unwinding::Return(return_from_index) => {
+                self.next_state = NextState::ReturnFrom(return_from_index);
+                Ok(())
             }
-            _ => {
-                let return_from_index = self.frames.len();
-                self.frames.push(Frame::Handler {
-                    handler,
-                    ip: 0,
-                    local_offset: local_offset - arity,
-                    self_value: target.clone(),
-                    target_value: target,
-                    return_from_index,
-                })
+            Unwinding::Error(error, trace) => {
+                self.resumed_trace = Some(trace);
+                Err(error)
             }
-        };
-        Ok(())
+        }
     }
     pub fn load_module(&mut self, module: &str) -> Runtime<Value> {
-        self.modules.load(module)
+        self.modules.load(module, self.limits, &mut self.budget)
     }
     pub fn get_stack(&mut self, address: Address) -> Value {
         self.stack[address].clone()
     }
-    pub fn deref_pointer(&self, pointer: Value) -> Value {
-        self.stack[pointer.as_pointer()].clone()
+    pub fn deref_pointer(&self, pointer: Value) -> Runtime<Value> {
+        Ok(self.stack[pointer.as_pointer()?].clone())
     }
-    pub fn set_pointer(&mut self, pointer: Value, value: Value) {
-        self.stack[pointer.as_pointer()] = value
+    pub fn set_pointer(&mut self, pointer: Value, value: Value) -> Runtime<()> {
+        self.stack[pointer.as_pointer()?] = value;
+        Ok(())
     }
     pub fn pop(&mut self) -> Value {
         self.stack.pop().unwrap()
@@ -326,9 +876,21 @@ impl<'a> Interpreter<'a> {
     pub fn self_value(&self) -> Value {
         self.top().self_value()
     }
-    pub fn get_ival(&self, index: usize) -> Value {
+    pub fn get_ival(&self, index: usize) -> Runtime<Value> {
         self.top().ival(index)
     }
+    // position of the send that called the current handler
+    pub fn call_site(&self) -> Option<Position> {
+        if let Frame::Handler {
+            tail_call_site: Some(position),
+            ..
+        } = self.top()
+        {
+            return Some(position.clone());
+        }
+        let caller = self.frames.len().checked_sub(2)?;
+        self.frames[caller].position().cloned()
+    }
     pub fn return_from_index(&self) -> usize {
         self.top().return_from_index()
     }
@@ -336,25 +898,49 @@ impl<'a> Interpreter<'a> {
         self.next_state = NextState::Return
     }
     pub fn do_loop(&mut self) {
-        self.top_mut().do_loop()
+        self.top_mut().jump(0)
+    }
+    pub fn jump(&mut self, index: usize) {
+        self.top_mut().jump(index)
+    }
+    // provided values last until the current frame returns
+    pub fn provide(&mut self, key: String, value: Value) {
+        self.top_mut().provided_mut().push((key, value));
+    }
+    pub fn unprovide(&mut self, count: usize) {
+        let provided = self.top_mut().provided_mut();
+        provided.truncate(provided.len() - count);
+    }
+    // the innermost provided value for key
+    pub fn use_provided(&self, key: &str) -> Runtime<Value> {
+        self.frames
+            .iter()
+            .rev()
+            .flat_map(|frame| frame.provided().iter().rev())
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| RuntimeError::NoProvider(key.to_string()))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::ir::{Class, Object, Param};
+    use crate::ir::{Class, InlineCache, Object, Param};
 
     use super::*;
 
     fn assert_ok(code: Vec<IR>, expected: Value) {
         let mut modules = ModuleLoader::new();
-        assert_eq!(Interpreter::program(code, &mut modules), Ok(expected));
+        assert_eq!(
+            Interpreter::program(code, &mut modules, Limits::default()),
+            Ok(expected)
+        );
     }
 
     fn assert_err(code: Vec<IR>, expected: RuntimeError) {
         let mut modules = ModuleLoader::new();
         assert_eq!(
-            Interpreter::program(code, &mut modules).map_err(|e| e.base_error()),
+            Interpreter::program(code, &mut modules, Limits::default()).map_err(|e| e.base_error()),
             Err(expected)
         );
     }
@@ -372,7 +958,7 @@ mod test {
     fn does_not_understand() {
         assert_err(
             vec![IR::int(1), IR::send("foobar", 0)],
-            RuntimeError::DoesNotUnderstand("foobar".to_string()),
+            RuntimeError::DoesNotUnderstand(Selector::new("foobar")),
         )
     }
 
@@ -412,7 +998,7 @@ mod test {
         let empty_class = Class::new().rc();
         assert_err(
             vec![IR::object(empty_class, 0), IR::send("foobar", 0)],
-            RuntimeError::DoesNotUnderstand("foobar".to_string()),
+            RuntimeError::DoesNotUnderstand(Selector::new("foobar")),
         )
     }
 
@@ -855,19 +1441,299 @@ mod test {
         assert_ok(
             vec![
                 IR::int(2),
-                IR::SendNative(|x, _| Ok(Value::Integer(x.as_int() << 2)), 0),
+                IR::SendNative(|x, _| Ok(Value::Integer(x.as_int()? << 2)), 0),
             ],
             Value::Integer(8),
         )
     }
 
+    #[test]
+    fn tail_calls() {
+        // counts down, then returns the number of frames
+        fn count(ctx: &mut Interpreter) -> Runtime<()> {
+            let n = ctx.pop().as_int()?;
+            if n == 0 {
+                ctx.push(Value::Integer(ctx.frames.len() as i64));
+                return Ok(());
+            }
+            ctx.push(Value::Integer(n - 1));
+            ctx.send(Selector::new("count:"), ctx.self_value(), 1)
+        }
+        let mut class = Class::new();
+        class.add(
+            "count:",
+            vec![Param::Value],
+            vec![IR::Local(0), IR::native(count)],
+        );
+        let obj = Value::Object(Object::new(class.rc(), vec![]).rc());
+        assert_ok(
+            vec![IR::int(1000), IR::Constant(obj), IR::send("count:", 1)],
+            Value::Integer(2),
+        );
+
+        // a handler with vars keeps its frame
+        let mut class = Class::new();
+        class.add(
+            "count:",
+            vec![Param::Value],
+            vec![IR::unit(), IR::Var(1), IR::Local(0), IR::native(count)],
+        );
+        let obj = Value::Object(Object::new(class.rc(), vec![]).rc());
+        assert_ok(
+            vec![IR::int(10), IR::Constant(obj), IR::send("count:", 1)],
+            Value::Integer(12),
+        );
+    }
+
+    #[test]
+    fn int_ops() {
+        let op = |ir: fn(InlineCache) -> IR| ir(InlineCache::default());
+        assert_ok(vec![IR::int(2), IR::int(3), op(IR::Add)], Value::Integer(5));
+        assert_ok(vec![IR::int(2), IR::int(3), op(IR::Sub)], Value::Integer(1));
+        assert_ok(vec![IR::int(2), IR::int(3), op(IR::Lt)], Value::Bool(false));
+        assert_ok(vec![IR::int(3), IR::int(3), op(IR::Eq)], Value::Bool(true));
+        // other values & overflows are sent
+        assert_ok(
+            vec![IR::float(0.5), IR::int(1), op(IR::Add)],
+            Value::Float(1.5),
+        );
+        assert_ok(
+            vec![IR::bool(true), IR::int(1), op(IR::Eq)],
+            Value::Bool(false),
+        );
+        let mut modules = ModuleLoader::new();
+        let result = Interpreter::program(
+            vec![IR::int(1), IR::int(i64::MAX), op(IR::Add)],
+            &mut modules,
+            Limits::default(),
+        );
+        assert!(matches!(result, Ok(Value::Bigint(_))));
+        assert_err(
+            vec![IR::int(1), IR::string("a".to_string()), op(IR::Sub)],
+            RuntimeError::DoesNotUnderstand(Selector::SUB),
+        );
+
+        let mut class = Class::new();
+        class.add("+:", vec![Param::Value], vec![IR::Local(0)]);
+        let obj = Value::Object(Object::new(class.rc(), vec![]).rc());
+        assert_ok(
+            vec![IR::int(1), IR::Constant(obj), op(IR::Add)],
+            Value::Integer(1),
+        );
+    }
+
+    #[test]
+    fn inline_caches() {
+        let object = |class: Class| Value::Object(Object::new(class.rc(), vec![]).rc());
+        let mut class = Class::new();
+        class.add("foo", vec![], vec![IR::int(1)]);
+        let one = object(class);
+        let mut class = Class::new();
+        class.add("foo", vec![], vec![IR::int(2)]);
+        let two = object(class);
+        let mut class = Class::new();
+        class.add(
+            "call:",
+            vec![Param::Value],
+            vec![IR::Local(0), IR::send("foo", 0)],
+        );
+        let caller = object(class);
+
+        crate::ir::reset_cache_stats();
+        let mut code = vec![];
+        for target in [&one, &two, &one, &two] {
+            code.push(IR::Constant(target.clone()));
+            code.push(IR::Constant(caller.clone()));
+            code.push(IR::send("call:", 1));
+            code.push(IR::Drop);
+        }
+        code.push(IR::unit());
+        assert_ok(code, Value::Unit);
+        // the send in `call:` misses once per class, each root send misses once
+        assert_eq!(
+            crate::ir::cache_stats(),
+            crate::ir::CacheStats { hits: 2, misses: 6 }
+        );
+    }
+
+    #[test]
+    fn inline_caches_of_recursive_handlers() {
+        // on {down: n} if n = 0 then () else self{down: n - 1} end
+        let mut class = Class::new();
+        class.add(
+            "down:",
+            vec![Param::Value],
+            vec![
+                IR::int(0),
+                IR::Local(0),
+                IR::send("=:", 1),
+                IR::JumpIfFalse(6),
+                IR::unit(),
+                IR::Jump(11),
+                IR::int(1),
+                IR::Local(0),
+                IR::send("-:", 1),
+                IR::SelfRef,
+                IR::send("down:", 1),
+            ],
+        );
+        let class = class.rc();
+        let handler = Rc::downgrade(&class.get(Selector::new("down:")).unwrap());
+        let obj = Value::Object(Object::new(class, vec![]).rc());
+        assert_ok(
+            vec![IR::int(3), IR::Constant(obj), IR::send("down:", 1)],
+            Value::Unit,
+        );
+        // the send to self is cached in the handler, but doesn't keep it alive
+        assert!(handler.upgrade().is_none());
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let limits = Limits {
+            max_frames: 100,
+            max_stack: 1000,
+        };
+        let program = |body: Vec<IR>| {
+            let mut class = Class::new();
+            class.add("loop", vec![], body);
+            let obj = Value::Object(Object::new(class.rc(), vec![]).rc());
+            vec![IR::Constant(obj), IR::send("loop", 0)]
+        };
+        let mut modules = ModuleLoader::new();
+        let result = Interpreter::program(
+            program(vec![IR::SelfRef, IR::send("loop", 0), IR::Drop, IR::unit()]),
+            &mut modules,
+            limits,
+        );
+        match result {
+            Err(RuntimeError::WithStackTrace(err, trace)) => {
+                assert_eq!(*err, RuntimeError::StackOverflow(100));
+                assert_eq!(trace.len(), OVERFLOW_TRACE_LEN);
+                assert_eq!(trace[0].selector, Some(Selector::new("loop")));
+            }
+            result => panic!("expected stack overflow, received {:?}", result),
+        }
+
+        let mut body = vec![IR::unit(); 50];
+        body.extend(vec![IR::SelfRef, IR::send("loop", 0), IR::Drop, IR::unit()]);
+        let result = Interpreter::program(program(body), &mut modules, limits);
+        assert_eq!(
+            result.map_err(|e| e.base_error()),
+            Err(RuntimeError::StackOverflow(22))
+        );
+
+        // a tail call that overflows is in the trace with the frame it would replace
+        let mut class = Class::new();
+        let mut a = vec![IR::int(1); 20];
+        a.extend([IR::SelfRef, IR::send("b", 0)]);
+        class.add("a", vec![], a);
+        let mut b = vec![IR::int(1); 10];
+        b.extend([IR::SelfRef, IR::send("a", 0), IR::Drop, IR::unit()]);
+        class.add("b", vec![], b);
+        let obj = Value::Object(Object::new(class.rc(), vec![]).rc());
+        let result = Interpreter::program(
+            vec![IR::Constant(obj), IR::send("a", 0)],
+            &mut modules,
+            Limits {
+                max_frames: 1000,
+                max_stack: 1000,
+            },
+        );
+        match result {
+            Err(RuntimeError::WithStackTrace(err, trace)) => {
+                assert!(matches!(*err, RuntimeError::StackOverflow(_)));
+                assert_eq!(trace.last().unwrap().selector, Some(Selector::new("a")));
+            }
+            result => panic!("expected stack overflow, received {:?}", result),
+        }
+    }
+
+    #[test]
+    fn fuel() {
+        let mut modules = ModuleLoader::new();
+        let (result, fuel) = Interpreter::program_with_fuel(
+            vec![IR::int(1), IR::int(2), add()],
+            &mut modules,
+            Limits::default(),
+            10,
+        );
+        assert_eq!(result, Ok(Value::Integer(3)));
+        // 3 instructions at the root & 2 in the native handler
+        assert_eq!(fuel, 5);
+
+        let (result, fuel) =
+            Interpreter::program_with_fuel(vec![IR::Loop], &mut modules, Limits::default(), 1000);
+        assert_eq!(
+            result.map_err(|e| e.base_error()),
+            Err(RuntimeError::OutOfFuel)
+        );
+        assert_eq!(fuel, 0);
+
+        // module initialization spends the importer's fuel
+        modules.add_init("loop", vec![IR::Loop]);
+        let (result, _) = Interpreter::program_with_fuel(
+            vec![IR::Module("loop".to_string())],
+            &mut modules,
+            Limits::default(),
+            1000,
+        );
+        assert_eq!(
+            result.map_err(|e| e.base_error()),
+            Err(RuntimeError::OutOfFuel)
+        );
+    }
+
+    #[test]
+    fn catch_payloads() {
+        assert_eq!(
+            RuntimeError::Panic(Value::Integer(1)).catch_payload(),
+            Some(Value::Integer(1))
+        );
+        assert_eq!(RuntimeError::DivisionByZero.catch_payload(), None);
+        assert_eq!(
+            RuntimeError::DoesNotUnderstand(Selector::new("foo")).catch_payload(),
+            None
+        );
+        assert_eq!(
+            RuntimeError::AssertionFailed(Value::Integer(1), Value::Integer(2), None)
+                .catch_payload(),
+            None
+        );
+        assert_eq!(RuntimeError::StackOverflow(1000).catch_payload(), None);
+        assert_eq!(RuntimeError::OutOfFuel.catch_payload(), None);
+        assert_eq!(RuntimeError::Interrupted.catch_payload(), None);
+    }
+
+    #[test]
+    fn interrupt() {
+        let interrupt = Interrupt::new();
+        let handle = interrupt.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            handle.interrupt();
+        });
+        let mut modules = ModuleLoader::new();
+        let result = Interpreter::new(&mut modules, Limits::default())
+            .with_interrupt(interrupt)
+            .run_program(vec![IR::Loop].into());
+        assert_eq!(
+            result.map_err(|e| e.base_error()),
+            Err(RuntimeError::Interrupted)
+        );
+    }
+
     #[test]
     fn modules() {
         let mut modules = ModuleLoader::new();
         modules.add_ready("foo", Value::Integer(123));
 
         assert_eq!(
-            Interpreter::program(vec![IR::Module("foo".to_string())], &mut modules),
+            Interpreter::program(
+                vec![IR::Module("foo".to_string())],
+                &mut modules,
+                Limits::default()
+            ),
             Ok(Value::Integer(123))
         );
     }
@@ -878,8 +1744,12 @@ mod test {
         modules.add_init("foo", vec![IR::Module("foo".to_string())]);
 
         assert_eq!(
-            Interpreter::program(vec![IR::Module("foo".to_string())], &mut modules)
-                .map_err(|e| e.base_error()),
+            Interpreter::program(
+                vec![IR::Module("foo".to_string())],
+                &mut modules,
+                Limits::default()
+            )
+            .map_err(|e| e.base_error()),
             Err(RuntimeError::ModuleLoadLoop("foo".to_string()))
         );
     }
@@ -892,6 +1762,26 @@ mod test {
         )
     }
 
+    #[test]
+    fn eval_keeps_locals() {
+        let mut modules = ModuleLoader::new();
+        let mut interpreter = Interpreter::new(&mut modules, Limits::default());
+        assert_eq!(
+            interpreter.eval(vec![IR::int(1), IR::unit()].into(), 1),
+            Ok(Value::Unit)
+        );
+        assert_eq!(
+            interpreter
+                .eval(vec![IR::int(2), IR::send("foo", 0)].into(), 2)
+                .map_err(|e| e.base_error()),
+            Err(RuntimeError::DoesNotUnderstand(Selector::new("foo")))
+        );
+        assert_eq!(
+            interpreter.eval(vec![IR::Local(0), IR::int(2), add()].into(), 1),
+            Ok(Value::Integer(3))
+        );
+    }
+
     #[test]
     fn try_send() {
         assert_ok(
@@ -905,7 +1795,7 @@ mod test {
                     0,
                 ),
                 IR::int(1),
-                IR::TrySend("unknown".to_string(), 0),
+                IR::TrySend(Selector::new("unknown"), 0),
             ],
             Value::Integer(123),
         )
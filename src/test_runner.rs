@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use crate::{eval, load_modules, Error};
+
+const TEST_SUFFIX: &str = ".test.gob";
+
+// the outcome of each test file, in the order they ran
+pub struct TestResults(pub Vec<(PathBuf, Result<(), Error>)>);
+
+impl TestResults {
+    pub fn passed(&self) -> impl Iterator<Item = &PathBuf> {
+        self.0
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .map(|(path, _)| path)
+    }
+    pub fn failed(&self) -> impl Iterator<Item = (&PathBuf, &Error)> {
+        self.0
+            .iter()
+            .filter_map(|(path, result)| result.as_ref().err().map(|err| (path, err)))
+    }
+}
+
+fn is_test_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.ends_with(TEST_SUFFIX))
+        .unwrap_or(false)
+}
+
+fn collect_tests(path: &Path, out: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let io_error = |err| Error::Io(path.display().to_string(), err);
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path).map_err(io_error)? {
+            collect_tests(&entry.map_err(io_error)?.path(), out)?;
+        }
+    } else if is_test_file(path) {
+        out.push(path.to_path_buf());
+    } else if !path.exists() {
+        std::fs::metadata(path).map_err(io_error)?;
+    }
+    Ok(())
+}
+
+// test files in paths (searching directories recursively) whose path contains filter
+pub fn find_tests(paths: &[PathBuf], filter: Option<&str>) -> Result<Vec<PathBuf>, Error> {
+    let mut out = vec![];
+    for path in paths {
+        collect_tests(path, &mut out)?;
+    }
+    if let Some(filter) = filter {
+        out.retain(|path| path.display().to_string().contains(filter));
+    }
+    out.sort();
+    out.dedup();
+    Ok(out)
+}
+
+// runs each test file with its own copy of the stdlib
+pub fn run_tests(files: Vec<PathBuf>) -> TestResults {
+    let results = files
+        .into_iter()
+        .map(|path| {
+            let result = std::fs::read_to_string(&path)
+                .map_err(|err| Error::Io(path.display().to_string(), err))
+                .and_then(|code| {
+                    let file = path.display().to_string();
+                    eval(&file, &code, &mut load_modules(Some(&path)))
+                });
+            (path, result.map(|_| ()))
+        })
+        .collect();
+    TestResults(results)
+}
+
+pub fn test(args: &[String]) -> Result<(), Error> {
+    let mut paths = vec![];
+    let mut filter = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--filter" | "-f" => match args.next() {
+                Some(value) => filter = Some(value.as_str()),
+                None => return Err(Error::Usage("expected filter after --filter".to_string())),
+            },
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        paths.push(PathBuf::from("."));
+    }
+
+    let results = run_tests(find_tests(&paths, filter)?);
+
+    for (path, result) in &results.0 {
+        let status = if result.is_ok() { "PASS" } else { "FAIL" };
+        println!("{} {}", status, path.display());
+    }
+    let failed = results.failed().count();
+    if failed > 0 {
+        println!("\nfailures:");
+        for (path, err) in results.failed() {
+            println!("\n{}\n{}", path.display(), err);
+        }
+    }
+    println!("\n{} passed, {} failed", results.passed().count(), failed);
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Error::TestsFailed(failed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_test_files() {
+        let dir = std::env::temp_dir().join("goblin_test_runner");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(
+            dir.join("pass.test.gob"),
+            "import [_Assert_] := \"core\"\nAssert{: 1 = 1}",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("nested/fail.test.gob"),
+            "import [_Assert_] := \"core\"\nAssert{: 1 = 2}",
+        )
+        .unwrap();
+        std::fs::write(dir.join("not_a_test.gob"), "x").unwrap();
+
+        let files = find_tests(std::slice::from_ref(&dir), None).unwrap();
+        assert_eq!(files.len(), 2);
+        let results = run_tests(files);
+        assert_eq!(
+            results.passed().collect::<Vec<_>>(),
+            vec![&dir.join("pass.test.gob")]
+        );
+        assert_eq!(results.failed().count(), 1);
+
+        let files = find_tests(&[dir], Some("pass")).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+}